@@ -0,0 +1,8 @@
+CREATE TABLE rate_limit_events
+(
+  id bigserial NOT NULL PRIMARY KEY,
+  kind character varying NOT NULL,
+  key character varying NOT NULL,
+  created_at timestamp with time zone NOT NULL DEFAULT now()
+);
+CREATE INDEX rate_limit_events_lookup ON rate_limit_events (kind, key, created_at);
@@ -0,0 +1,3 @@
+ALTER TABLE monitors DROP COLUMN created_at;
+ALTER TABLE monitors DROP COLUMN confirmed_at;
+ALTER TABLE monitors DROP COLUMN confirmed_via;
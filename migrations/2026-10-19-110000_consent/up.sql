@@ -0,0 +1,4 @@
+-- For monitors that were added before this was recorded, these remain NULL
+ALTER TABLE monitors ADD COLUMN created_at timestamp with time zone;
+ALTER TABLE monitors ADD COLUMN confirmed_at timestamp with time zone;
+ALTER TABLE monitors ADD COLUMN confirmed_via character varying;
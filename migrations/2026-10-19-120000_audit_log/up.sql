@@ -0,0 +1,12 @@
+CREATE TABLE audit_log
+(
+  id bigserial NOT NULL PRIMARY KEY,
+  created_at timestamp with time zone NOT NULL DEFAULT now(),
+  email character varying NOT NULL,
+  node character varying NOT NULL,
+  action character varying NOT NULL,
+  via character varying NOT NULL,
+  client_ip_hash character varying
+);
+CREATE INDEX audit_log_email ON audit_log (email, created_at);
+CREATE INDEX audit_log_node ON audit_log (node, created_at);
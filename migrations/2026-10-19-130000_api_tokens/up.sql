@@ -0,0 +1,10 @@
+CREATE TABLE api_tokens
+(
+  id bigserial NOT NULL PRIMARY KEY,
+  email character varying NOT NULL,
+  token_hash character varying NOT NULL UNIQUE,
+  label character varying NOT NULL,
+  created_at timestamp with time zone NOT NULL DEFAULT now(),
+  last_used_at timestamp with time zone
+);
+CREATE INDEX api_tokens_email ON api_tokens (email);
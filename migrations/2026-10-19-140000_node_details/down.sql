@@ -0,0 +1,9 @@
+DROP TABLE node_events;
+
+ALTER TABLE nodes
+  DROP COLUMN first_seen,
+  DROP COLUMN last_seen,
+  DROP COLUMN clients,
+  DROP COLUMN loadavg,
+  DROP COLUMN memory_usage,
+  DROP COLUMN rootfs_usage;
@@ -0,0 +1,16 @@
+ALTER TABLE nodes
+  ADD COLUMN first_seen timestamp with time zone,
+  ADD COLUMN last_seen timestamp with time zone,
+  ADD COLUMN clients integer,
+  ADD COLUMN loadavg double precision,
+  ADD COLUMN memory_usage double precision,
+  ADD COLUMN rootfs_usage double precision;
+
+CREATE TABLE node_events
+(
+  id bigserial NOT NULL PRIMARY KEY,
+  node character varying NOT NULL,
+  online boolean NOT NULL,
+  created_at timestamp with time zone NOT NULL DEFAULT now()
+);
+CREATE INDEX node_events_node ON node_events (node, created_at);
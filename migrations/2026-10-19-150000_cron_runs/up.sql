@@ -0,0 +1,9 @@
+CREATE TABLE cron_runs
+(
+  id bigserial NOT NULL PRIMARY KEY,
+  created_at timestamp with time zone NOT NULL DEFAULT now(),
+  outcome character varying NOT NULL,
+  online_nodes integer,
+  message character varying
+);
+CREATE INDEX cron_runs_outcome ON cron_runs (outcome, created_at);
@@ -0,0 +1,9 @@
+CREATE TABLE webhooks
+(
+  id bigserial NOT NULL PRIMARY KEY,
+  email character varying NOT NULL,
+  url character varying NOT NULL,
+  secret character varying NOT NULL,
+  created_at timestamp with time zone NOT NULL DEFAULT now()
+);
+CREATE INDEX webhooks_email ON webhooks (email);
@@ -0,0 +1 @@
+DROP TABLE matrix_rooms;
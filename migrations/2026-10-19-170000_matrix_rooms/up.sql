@@ -0,0 +1,9 @@
+CREATE TABLE matrix_rooms
+(
+  id bigserial NOT NULL PRIMARY KEY,
+  email character varying NOT NULL,
+  target character varying NOT NULL,
+  room_id character varying NOT NULL,
+  created_at timestamp with time zone NOT NULL DEFAULT now()
+);
+CREATE INDEX matrix_rooms_email ON matrix_rooms (email);
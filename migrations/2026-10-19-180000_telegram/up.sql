@@ -0,0 +1,14 @@
+CREATE TABLE telegram_links
+(
+  token_hash character varying NOT NULL PRIMARY KEY,
+  email character varying NOT NULL,
+  created_at timestamp with time zone NOT NULL DEFAULT now()
+);
+CREATE TABLE telegram_chats
+(
+  id bigserial NOT NULL PRIMARY KEY,
+  email character varying NOT NULL,
+  chat_id bigint NOT NULL UNIQUE,
+  created_at timestamp with time zone NOT NULL DEFAULT now()
+);
+CREATE INDEX telegram_chats_email ON telegram_chats (email);
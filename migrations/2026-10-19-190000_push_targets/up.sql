@@ -0,0 +1,10 @@
+CREATE TABLE push_targets
+(
+  id bigserial NOT NULL PRIMARY KEY,
+  email character varying NOT NULL,
+  kind character varying NOT NULL,
+  url character varying NOT NULL,
+  token character varying,
+  created_at timestamp with time zone NOT NULL DEFAULT now()
+);
+CREATE INDEX push_targets_email ON push_targets (email);
@@ -0,0 +1 @@
+ALTER TABLE nodes ADD COLUMN domain character varying;
@@ -0,0 +1,5 @@
+CREATE TABLE session_resets
+(
+  email character varying NOT NULL PRIMARY KEY,
+  reset_at timestamp with time zone NOT NULL
+);
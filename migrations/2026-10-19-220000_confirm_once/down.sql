@@ -0,0 +1,3 @@
+ALTER TABLE push_targets DROP CONSTRAINT push_targets_email_kind_url;
+ALTER TABLE webhooks DROP CONSTRAINT webhooks_email_url;
+ALTER TABLE api_tokens DROP COLUMN request_id;
@@ -0,0 +1,6 @@
+ALTER TABLE api_tokens ADD COLUMN request_id character varying UNIQUE;
+DELETE FROM webhooks a USING webhooks b WHERE a.email = b.email AND a.url = b.url AND a.id > b.id;
+ALTER TABLE webhooks ADD CONSTRAINT webhooks_email_url UNIQUE (email, url);
+DELETE FROM push_targets a USING push_targets b
+  WHERE a.email = b.email AND a.kind = b.kind AND a.url = b.url AND a.id > b.id;
+ALTER TABLE push_targets ADD CONSTRAINT push_targets_email_kind_url UNIQUE (email, kind, url);
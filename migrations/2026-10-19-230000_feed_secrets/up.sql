@@ -0,0 +1,6 @@
+CREATE TABLE feed_secrets
+(
+  email character varying NOT NULL PRIMARY KEY,
+  secret character varying NOT NULL,
+  created_at timestamp with time zone NOT NULL DEFAULT now()
+);
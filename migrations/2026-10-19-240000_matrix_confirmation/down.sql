@@ -0,0 +1 @@
+ALTER TABLE matrix_rooms DROP COLUMN confirmation_code_hash;
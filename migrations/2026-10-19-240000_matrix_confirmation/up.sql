@@ -0,0 +1,2 @@
+-- Rooms with a code are waiting for the code to be entered on the list page
+ALTER TABLE matrix_rooms ADD COLUMN confirmation_code_hash character varying;
@@ -0,0 +1,11 @@
+-- Push targets waiting for email confirmation.  The emailed link only carries the ID, so that the
+-- token does not end up in anyone's mailbox.
+CREATE TABLE pending_push_targets
+(
+  id character varying NOT NULL PRIMARY KEY,
+  email character varying NOT NULL,
+  kind character varying NOT NULL,
+  url character varying NOT NULL,
+  token character varying,
+  valid_until timestamp with time zone NOT NULL
+);
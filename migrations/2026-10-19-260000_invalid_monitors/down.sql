@@ -0,0 +1,4 @@
+INSERT INTO monitors (id, email, created_at, confirmed_at, confirmed_via)
+  SELECT id, email, created_at, confirmed_at, confirmed_via FROM invalid_monitors
+  ON CONFLICT DO NOTHING;
+DROP TABLE invalid_monitors;
@@ -0,0 +1,12 @@
+-- Monitors of addresses that are not valid (any more), moved here at startup so that we do not try
+-- to send emails to them.  Kept so that the admin can look at them.
+CREATE TABLE invalid_monitors
+(
+  id character varying NOT NULL,
+  email character varying NOT NULL,
+  created_at timestamp with time zone,
+  confirmed_at timestamp with time zone,
+  confirmed_via character varying,
+  removed_at timestamp with time zone NOT NULL DEFAULT now(),
+  PRIMARY KEY (id, email)
+);
@@ -14,21 +14,85 @@
 //  You should have received a copy of the GNU Affero General Public License
 //  along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+use std::net::IpAddr;
+
 use rocket::form::FromFormField;
 use rocket::FromForm;
 
-use anyhow::{bail, Result};
+use anyhow::Result;
+use base64::Engine as _;
+use chrono::{DateTime, Utc};
 use diesel::prelude::*;
-use diesel::result::{DatabaseErrorKind, Error as DieselError};
 use ring::{error, hmac};
+use rmp_serde::from_slice as deserialize_from_slice;
 use rmp_serde::to_vec as serialize_to_vec;
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_repr::{Deserialize_repr, Serialize_repr};
 
+use crate::config::Secrets;
 use crate::db::DbConn;
 use crate::email::EmailAddress;
+use crate::feed;
 use crate::models::*;
 use crate::schema::*;
+use crate::session;
+use crate::util::hash_client_ip;
+
+const BASE64_ENGINE: base64::engine::GeneralPurpose =
+    base64::engine::general_purpose::URL_SAFE_NO_PAD;
+
+/// Data that we hand out to users after signing it, so that we can trust it when it comes back
+pub trait Signable: Serialize + DeserializeOwned {
+    /// Makes sure a signature for one kind of data is never valid for another kind
+    const PURPOSE: &'static str;
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Signed<T> {
+    /// ID of the key the signature was made with
+    key_id: String,
+    data: T,
+    signature: Box<[u8]>,
+}
+
+fn signed_bytes<T: Signable>(data: &T) -> Vec<u8> {
+    serialize_to_vec(&(T::PURPOSE, data)).expect("failed to encode signed data")
+}
+
+impl<T: Signable> Signed<T> {
+    pub fn sign(data: T, secrets: &Secrets) -> Self {
+        let (key_id, key) = secrets.current_signing_key();
+        let signature = hmac::sign(key, signed_bytes(&data).as_slice());
+        let signature = signature.as_ref().to_vec().into_boxed_slice();
+        Signed {
+            key_id: key_id.to_owned(),
+            data,
+            signature,
+        }
+    }
+
+    pub fn verify(self, secrets: &Secrets) -> Result<T, error::Unspecified> {
+        // Keys that have been rotated out can no longer verify anything
+        let key = secrets
+            .signing_key(&self.key_id)
+            .ok_or(error::Unspecified)?;
+        // Using a match to make it really clear we don't return the data in case of failure
+        match hmac::verify(key, signed_bytes(&self.data).as_slice(), &self.signature) {
+            Ok(_) => Ok(self.data),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Encode into a string that can be used in URLs
+    pub fn encode(&self) -> String {
+        BASE64_ENGINE.encode(serialize_to_vec(self).expect("failed to encode signed data"))
+    }
+
+    pub fn decode(s: &str) -> Result<Self> {
+        let bytes = BASE64_ENGINE.decode(s)?;
+        Ok(deserialize_from_slice(bytes.as_slice())?)
+    }
+}
 
 #[derive(Serialize_repr, Deserialize_repr, PartialEq, Debug, Copy, Clone, FromFormField)]
 #[repr(u8)]
@@ -37,81 +101,377 @@ pub enum Operation {
     Remove = 0,
 }
 
+impl Operation {
+    /// The name used for this operation in the audit log
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Operation::Add => "add",
+            Operation::Remove => "remove",
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, FromForm, Clone)]
 pub struct Action {
-    pub node: String,
+    #[field(validate = len(1..))]
+    pub nodes: Vec<String>,
     pub email: EmailAddress,
     pub op: Operation,
 }
 
+/// An action waiting for confirmation by email
 #[derive(Serialize, Deserialize)]
-pub struct SignedAction {
-    action: Action,
-    signature: Box<[u8]>,
+pub struct PendingAction {
+    pub action: Action,
+    /// Unix timestamp of when the confirmation email was sent
+    pub requested_at: i64,
 }
 
-impl Action {
-    fn compute_signature(&self, key: &hmac::Key) -> hmac::Tag {
-        let buf = serialize_to_vec(self).expect("failed to encode Action");
-        hmac::sign(key, buf.as_slice())
+impl Signable for PendingAction {
+    const PURPOSE: &'static str = "action-v3";
+}
+
+pub type SignedAction = Signed<PendingAction>;
+
+/// How an action was confirmed, kept as proof of consent to monitoring
+pub struct Confirmation {
+    /// When confirmation was requested, if known
+    pub requested_at: Option<DateTime<Utc>>,
+    pub confirmed_at: DateTime<Utc>,
+    /// "email" for confirmation links, "session" for logged-in users, "change-email" for monitors
+    /// moved to a new address, "unsubscribe" for the unsubscribe-all link, "api-token" for API
+    /// requests authenticated with a personal token, "telegram" for commands sent by a connected
+    /// Telegram chat
+    pub via: &'static str,
+    /// Hash of the IP address of the client that confirmed
+    pub client_ip_hash: Option<String>,
+}
+
+impl Confirmation {
+    pub fn now(
+        secrets: &Secrets,
+        requested_at: Option<DateTime<Utc>>,
+        via: &'static str,
+        client_ip: Option<IpAddr>,
+    ) -> Self {
+        Confirmation {
+            requested_at,
+            confirmed_at: Utc::now(),
+            via,
+            client_ip_hash: hash_client_ip(secrets, client_ip),
+        }
     }
 
-    fn verify_signature(
+    /// Record in the audit log that this confirmation added/removed `node` for `email`
+    fn audit(
         &self,
-        key: &hmac::Key,
-        signature: &[u8],
-    ) -> Result<(), error::Unspecified> {
-        let buf = serialize_to_vec(self).expect("failed to encode Action");
-        hmac::verify(key, buf.as_slice(), signature)
+        db: &mut PgConnection,
+        email: &str,
+        node: &str,
+        op: Operation,
+    ) -> QueryResult<()> {
+        diesel::insert_into(audit_log::table)
+            .values(&AuditLogEntry {
+                created_at: self.confirmed_at,
+                email,
+                node,
+                action: op.as_str(),
+                via: self.via,
+                client_ip_hash: self.client_ip_hash.as_deref(),
+            })
+            .execute(db)?;
+        Ok(())
     }
+}
 
-    pub fn sign(self, key: &hmac::Key) -> SignedAction {
-        let signature = self.compute_signature(key);
-        let signature = signature.as_ref().to_vec().into_boxed_slice();
-        SignedAction {
-            action: self,
-            signature,
-        }
+/// What running an action did to one of its nodes
+#[derive(Serialize)]
+pub struct NodeOutcome {
+    pub node: String,
+    /// Whether this changed anything (i.e., the node was not already (not) monitored)
+    pub success: bool,
+}
+
+/// Grants access to the list of nodes monitored by `email`, until the given time
+#[derive(Serialize, Deserialize)]
+pub struct ListAccess {
+    pub email: EmailAddress,
+    /// Unix timestamp
+    pub valid_until: i64,
+}
+
+impl Signable for ListAccess {
+    const PURPOSE: &'static str = "list";
+}
+
+/// Read the state changes of all nodes monitored by `email`.  Only valid as long as `secret` is
+/// the current feed secret of `email`.
+#[derive(Serialize, Deserialize)]
+pub struct SubscriberFeed {
+    pub email: EmailAddress,
+    pub secret: String,
+}
+
+impl Signable for SubscriberFeed {
+    const PURPOSE: &'static str = "feed";
+}
+
+/// Stop monitoring any node for `email`, and remove all other ways of notifying `email` and of
+/// acting on its behalf
+#[derive(Serialize, Deserialize)]
+pub struct UnsubscribeAll {
+    pub email: EmailAddress,
+}
+
+impl Signable for UnsubscribeAll {
+    const PURPOSE: &'static str = "unsubscribe";
+}
+
+/// Move all monitors from `from` to `to`.  Both addresses need to confirm this: first `from`,
+/// then `to`.
+#[derive(Serialize, Deserialize)]
+pub struct ChangeEmail {
+    pub from: EmailAddress,
+    pub to: EmailAddress,
+    /// Whether `from` has already confirmed
+    pub from_confirmed: bool,
+    /// Unix timestamp
+    pub valid_until: i64,
+}
+
+impl Signable for ChangeEmail {
+    const PURPOSE: &'static str = "change-email";
+}
+
+impl Action {
+    /// Bring the node list into a canonical form: sorted, without duplicates
+    pub fn normalize(&mut self) {
+        self.nodes.sort();
+        self.nodes.dedup();
     }
 
-    pub async fn run(&self, db: &DbConn) -> Result<bool> {
+    pub async fn run(&self, db: &DbConn, confirmation: Confirmation) -> Result<Vec<NodeOutcome>> {
         let op = self.op;
-        let node = self.node.clone();
+        let nodes = self.nodes.clone();
         let email = self.email.clone();
         db.run_transaction(move |db| {
-            let m = Monitor {
-                id: node.as_str(),
-                email: &email,
-            };
-            Ok(match op {
-                Operation::Add => {
-                    // Add node.  We are fine if it does not exist.
-                    let r = diesel::insert_into(monitors::table).values(&m).execute(db);
-                    // Handle UniqueViolation gracefully
-                    match r {
-                        Ok(_) => true,
-                        Err(DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => {
-                            false
-                        }
-                        Err(e) => bail!(e),
+            let mut outcomes = Vec::new();
+            for node in nodes.into_iter() {
+                let m = Monitor {
+                    id: node.as_str(),
+                    email: &email,
+                    created_at: confirmation.requested_at,
+                    confirmed_at: Some(confirmation.confirmed_at),
+                    confirmed_via: Some(confirmation.via),
+                };
+                let success = match op {
+                    Operation::Add => {
+                        // Add node.  We are fine if it does not exist.
+                        let num_inserted = diesel::insert_into(monitors::table)
+                            .values(&m)
+                            .on_conflict_do_nothing()
+                            .execute(db)?;
+                        num_inserted > 0
                     }
+                    Operation::Remove => {
+                        let num_deleted = diesel::delete(&m).execute(db)?;
+                        num_deleted > 0
+                    }
+                };
+                if success {
+                    confirmation.audit(db, &email, &node, op)?;
                 }
-                Operation::Remove => {
-                    let num_deleted = diesel::delete(&m).execute(db)?;
-                    num_deleted > 0
-                }
+                outcomes.push(NodeOutcome { node, success });
+            }
+            Ok(outcomes)
+        })
+        .await
+    }
+}
+
+/// Move all monitors of `from` over to `to`.  Nodes monitored by both remain monitored once.
+/// If `to` confirmed this, that is recorded (also in the audit log); otherwise the old
+/// confirmation data is kept.
+pub fn move_monitors(
+    db: &mut PgConnection,
+    from: &str,
+    to: &str,
+    confirmation: Option<&Confirmation>,
+) -> QueryResult<()> {
+    let monitors = monitors::table
+        .filter(monitors::email.eq(from))
+        .load::<MonitorQuery>(db)?;
+    for m in monitors.iter() {
+        let (confirmed_at, confirmed_via) = match confirmation {
+            Some(confirmation) => (Some(confirmation.confirmed_at), Some(confirmation.via)),
+            None => (m.confirmed_at, m.confirmed_via.as_deref()),
+        };
+        let num_inserted = diesel::insert_into(monitors::table)
+            .values(&Monitor {
+                id: &m.id,
+                email: to,
+                created_at: m.created_at,
+                confirmed_at,
+                confirmed_via,
             })
+            .on_conflict_do_nothing()
+            .execute(db)?;
+        if let Some(confirmation) = confirmation {
+            confirmation.audit(db, from, &m.id, Operation::Remove)?;
+            if num_inserted > 0 {
+                confirmation.audit(db, to, &m.id, Operation::Add)?;
+            }
+        }
+    }
+    diesel::delete(monitors::table.filter(monitors::email.eq(from))).execute(db)?;
+    Ok(())
+}
+
+impl UnsubscribeAll {
+    /// Returns the number of nodes that are no longer monitored
+    pub async fn run(&self, db: &DbConn, confirmation: Confirmation) -> Result<usize> {
+        let email = self.email.clone();
+        db.run_transaction(move |db| {
+            let nodes = diesel::delete(monitors::table.filter(monitors::email.eq(&*email)))
+                .returning(monitors::id)
+                .get_results::<String>(db)?;
+            for node in nodes.iter() {
+                confirmation.audit(db, &email, node, Operation::Remove)?;
+            }
+            // Notifications would also go to these, and they could add nodes again
+            diesel::delete(webhooks::table.filter(webhooks::email.eq(&*email))).execute(db)?;
+            diesel::delete(push_targets::table.filter(push_targets::email.eq(&*email)))
+                .execute(db)?;
+            diesel::delete(
+                pending_push_targets::table.filter(pending_push_targets::email.eq(&*email)),
+            )
+            .execute(db)?;
+            diesel::delete(matrix_rooms::table.filter(matrix_rooms::email.eq(&*email)))
+                .execute(db)?;
+            diesel::delete(telegram_chats::table.filter(telegram_chats::email.eq(&*email)))
+                .execute(db)?;
+            diesel::delete(telegram_links::table.filter(telegram_links::email.eq(&*email)))
+                .execute(db)?;
+            diesel::delete(api_tokens::table.filter(api_tokens::email.eq(&*email))).execute(db)?;
+            feed::reset(db, &email)?;
+            session::reset(db, &email)?;
+            Ok(nodes.len())
         })
         .await
     }
 }
 
-impl SignedAction {
-    pub fn verify(self, key: &hmac::Key) -> Result<Action, error::Unspecified> {
-        // Using a match to make it really clear we don't return the action in case of failure
-        match self.action.verify_signature(key, &self.signature) {
-            Ok(_) => Ok(self.action),
-            Err(e) => Err(e),
+impl ChangeEmail {
+    pub async fn run(&self, db: &DbConn, confirmation: Confirmation) -> Result<()> {
+        assert!(self.from_confirmed);
+        let from = self.from.clone();
+        let to = self.to.clone();
+        db.run_transaction(move |db| {
+            move_monitors(db, &from, &to, Some(&confirmation))?;
+            // Whoever is logged in as the old address should no longer be
+            session::reset(db, &from)?;
+            feed::reset(db, &from)?;
+            Ok(())
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secrets(keys: &[(&str, &str)], current: &str) -> Secrets {
+        let keys: serde_json::Map<String, serde_json::Value> = keys
+            .iter()
+            .map(|(id, key)| (id.to_string(), serde_json::Value::from(*key)))
+            .collect();
+        serde_json::from_value(serde_json::json!({
+            "action_signing_keys": keys,
+            "action_signing_key_id": current,
+            "personal_data_key": "00",
+        }))
+        .unwrap()
+    }
+
+    fn list_access() -> ListAccess {
+        ListAccess {
+            email: EmailAddress::new("a@example.org".to_owned()).unwrap(),
+            valid_until: 42,
         }
     }
+
+    #[test]
+    fn sign_and_verify() {
+        let secrets = secrets(&[("a", "00112233")], "a");
+        let token = Signed::sign(list_access(), &secrets).encode();
+        let access = Signed::<ListAccess>::decode(&token)
+            .unwrap()
+            .verify(&secrets)
+            .unwrap();
+        assert_eq!(&*access.email, "a@example.org");
+        assert_eq!(access.valid_until, 42);
+    }
+
+    #[test]
+    fn reject_tampered_data() {
+        let secrets = secrets(&[("a", "00112233")], "a");
+        let mut signed = Signed::sign(list_access(), &secrets);
+        signed.data.valid_until += 1;
+        assert!(signed.verify(&secrets).is_err());
+    }
+
+    /// The same data as [`ListAccess`], for another purpose
+    #[derive(Serialize, Deserialize)]
+    struct OtherAccess {
+        email: EmailAddress,
+        valid_until: i64,
+    }
+
+    impl Signable for OtherAccess {
+        const PURPOSE: &'static str = "other";
+    }
+
+    #[test]
+    fn reject_other_purpose() {
+        let secrets = secrets(&[("a", "00112233")], "a");
+        let token = Signed::sign(list_access(), &secrets).encode();
+        // Same serialized data, different purpose
+        let signed = Signed::<OtherAccess>::decode(&token).unwrap();
+        assert!(signed.verify(&secrets).is_err());
+    }
+
+    #[test]
+    fn key_rotation() {
+        let old = secrets(&[("a", "00112233")], "a");
+        let rotated = secrets(&[("a", "00112233"), ("b", "44556677")], "b");
+        let retired = secrets(&[("b", "44556677")], "b");
+
+        let old_token = Signed::sign(list_access(), &old).encode();
+        let new_token = Signed::sign(list_access(), &rotated).encode();
+        assert_eq!(
+            Signed::<ListAccess>::decode(&new_token).unwrap().key_id,
+            "b"
+        );
+
+        // While the old key is still configured, its signatures remain valid
+        for token in [&old_token, &new_token] {
+            let signed = Signed::<ListAccess>::decode(token).unwrap();
+            assert!(signed.verify(&rotated).is_ok());
+        }
+        // Once it is removed, they no longer are
+        let signed = Signed::<ListAccess>::decode(&old_token).unwrap();
+        assert!(signed.verify(&retired).is_err());
+        let signed = Signed::<ListAccess>::decode(&new_token).unwrap();
+        assert!(signed.verify(&retired).is_ok());
+    }
+
+    #[test]
+    fn reject_unknown_key_with_same_id() {
+        let ours = secrets(&[("a", "00112233")], "a");
+        let theirs = secrets(&[("a", "8899aabb")], "a");
+        let token = Signed::sign(list_access(), &theirs).encode();
+        let signed = Signed::<ListAccess>::decode(&token).unwrap();
+        assert!(signed.verify(&ours).is_err());
+    }
 }
@@ -0,0 +1,192 @@
+//  ff-node-monitor -- Monitoring for Freifunk nodes
+//  Copyright (C) 2018  Ralf Jung <post AT ralfj DOT de>
+//
+//  This program is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU Affero General Public License as published by
+//  the Free Software Foundation, either version 3 of the License, or
+//  (at your option) any later version.
+//
+//  This program is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//  GNU Affero General Public License for more details.
+//
+//  You should have received a copy of the GNU Affero General Public License
+//  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::config::{self, Config};
+use crate::db::DbConn;
+use crate::notify::{Event, Notifier};
+use crate::util::Ctx;
+
+/// The `alertname` label of our alerts
+const ALERT_NAME: &str = "FreifunkNodeOffline";
+/// How often we try to post an alert, and how long we wait before each retry
+const RETRY_DELAYS: [u64; 3] = [0, 10, 60];
+
+/// An alert in the format of the Alertmanager v2 API
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Alert {
+    labels: BTreeMap<String, String>,
+    annotations: BTreeMap<String, String>,
+    starts_at: DateTime<Utc>,
+    ends_at: DateTime<Utc>,
+    #[serde(rename = "generatorURL")]
+    generator_url: String,
+}
+
+/// The alert for a node that went offline or came back.  Alertmanager identifies alerts by their
+/// labels, so the resolving alert must have the same labels as the firing one.  The node ID and
+/// the domain (which alerts are routed and grouped by) are labels.  The hostname is only an
+/// annotation: nodes get renamed much more often, and a rename while the node is offline would
+/// leave the old alert firing next to the new one.
+fn alert(alertmanager: &config::Alertmanager, event: &Event<'_>, summary: &str) -> Alert {
+    let mut labels = alertmanager.labels.clone();
+    labels.insert("alertname".to_owned(), ALERT_NAME.to_owned());
+    labels.insert("node_id".to_owned(), event.node.id.clone());
+    if let Some(domain) = &event.node.domain {
+        labels.insert("domain".to_owned(), domain.clone());
+    }
+    let annotations = BTreeMap::from([
+        ("summary".to_owned(), summary.to_owned()),
+        ("hostname".to_owned(), event.node.name.clone()),
+    ]);
+    // A firing alert expires by itself after a while, so that nodes that never come back do not
+    // alert forever.  Alertmanager keeps the original start time when resolving.
+    let ends_at = if event.online() {
+        event.timestamp
+    } else {
+        event.timestamp + chrono::Duration::seconds(alertmanager.alert_lifetime.into())
+    };
+    Alert {
+        labels,
+        annotations,
+        starts_at: event.timestamp,
+        ends_at,
+        generator_url: event.node_url.clone(),
+    }
+}
+
+/// Alerts for nodes going offline, resolved when they come back.  Unlike the other channels, this
+/// covers all nodes, not just monitored ones.  The alerts of a cron run are collected and posted
+/// together when it is done.
+pub struct AlertmanagerNotifier {
+    client: reqwest::Client,
+    alerts: Mutex<Vec<Alert>>,
+}
+
+impl AlertmanagerNotifier {
+    /// The notifier, if Alertmanager is configured
+    pub fn new(config: &Config) -> Result<Option<Self>> {
+        if config.alertmanager.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(AlertmanagerNotifier {
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(10))
+                .build()?,
+            alerts: Mutex::new(Vec::new()),
+        }))
+    }
+}
+
+#[rocket::async_trait]
+impl Notifier for AlertmanagerNotifier {
+    async fn notify(
+        &self,
+        ctx: &Ctx<'_>,
+        _db: &DbConn,
+        event: &Event<'_>,
+        _subscribers: &[String],
+    ) -> Result<()> {
+        // `new` made sure this is configured
+        let alertmanager = ctx.config().alertmanager.as_ref().unwrap();
+        let text = event.text(ctx)?;
+        let summary = text.lines().next().unwrap_or_default();
+        let alert = alert(alertmanager, event, summary);
+        self.alerts.lock().unwrap().push(alert);
+        Ok(())
+    }
+
+    async fn finish(&self, ctx: &Ctx<'_>) -> Result<()> {
+        let alerts = std::mem::take(&mut *self.alerts.lock().unwrap());
+        if alerts.is_empty() {
+            return Ok(());
+        }
+        let alertmanager = ctx.config().alertmanager.as_ref().unwrap();
+
+        // Post in the background, retrying a few times on failure
+        let mut url = alertmanager.url.clone();
+        url.path_segments_mut()
+            .expect("the Alertmanager URL cannot be a base")
+            .pop_if_empty()
+            .extend(["api", "v2", "alerts"]);
+        let request = self.client.post(url).json(&alerts);
+        let num = alerts.len();
+        rocket::tokio::spawn(async move {
+            for delay in RETRY_DELAYS {
+                rocket::tokio::time::sleep(Duration::from_secs(delay)).await;
+                let response = request
+                    .try_clone()
+                    .expect("alert requests have a fixed body")
+                    .send()
+                    .await
+                    .and_then(|response| response.error_for_status());
+                match response {
+                    Ok(_) => return,
+                    Err(e) => rocket::warn!("Posting {} alerts failed: {}", num, e),
+                }
+            }
+            rocket::error!("Giving up posting {} alerts", num);
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::NodeQuery;
+
+    #[test]
+    fn labels_for_routing() {
+        let alertmanager: config::Alertmanager = serde_json::from_value(serde_json::json!({
+            "url": "http://alertmanager.example.org/",
+            "labels": { "severity": "warning" },
+        }))
+        .unwrap();
+        let node = NodeQuery {
+            id: "aa01".to_owned(),
+            name: "node-one".to_owned(),
+            online: false,
+            first_seen: None,
+            last_seen: None,
+            clients: None,
+            loadavg: None,
+            memory_usage: None,
+            rootfs_usage: None,
+            domain: Some("west".to_owned()),
+        };
+        let event = Event {
+            node: &node,
+            was_online: true,
+            timestamp: Utc::now(),
+            node_url: String::new(),
+        };
+        let alert = alert(&alertmanager, &event, "node-one is offline");
+        let labels: Vec<&str> = alert.labels.keys().map(String::as_str).collect();
+        assert_eq!(labels, ["alertname", "domain", "node_id", "severity"]);
+        assert_eq!(alert.labels["domain"], "west");
+        assert_eq!(alert.labels["node_id"], "aa01");
+        assert_eq!(alert.annotations["hostname"], "node-one");
+    }
+}
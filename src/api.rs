@@ -0,0 +1,325 @@
+//  ff-node-monitor -- Monitoring for Freifunk nodes
+//  Copyright (C) 2018  Ralf Jung <post AT ralfj DOT de>
+//
+//  This program is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU Affero General Public License as published by
+//  the Free Software Foundation, either version 3 of the License, or
+//  (at your option) any later version.
+//
+//  This program is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//  GNU Affero General Public License for more details.
+//
+//  You should have received a copy of the GNU Affero General Public License
+//  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::net::IpAddr;
+
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use rocket::http::Status;
+use rocket::response::{self, Responder};
+use rocket::serde::json::{self, Json};
+use rocket::{catch, catchers, get, post, routes, Request};
+
+use crate::action::*;
+use crate::apitoken::Bearer;
+use crate::db::DbConn;
+use crate::email::EmailAddress;
+use crate::models::*;
+use crate::routes::{action_nodes, check_list_access, send_confirmation};
+use crate::session::Session;
+use crate::util::{hash_client_ip, Ctx};
+
+/// An error as returned by the API: a status code, plus a JSON body with a machine-readable
+/// `error` code and a human-readable `message`.
+pub struct ApiError {
+    status: Status,
+    error: &'static str,
+    message: String,
+    /// Further information about the error
+    details: Option<serde_json::Value>,
+}
+
+impl ApiError {
+    fn new(status: Status, error: &'static str, message: impl Into<String>) -> Self {
+        ApiError {
+            status,
+            error,
+            message: message.into(),
+            details: None,
+        }
+    }
+
+    fn with_details(mut self, details: serde_json::Value) -> Self {
+        self.details = Some(details);
+        self
+    }
+}
+
+impl<'r> Responder<'r, 'static> for ApiError {
+    fn respond_to(self, r: &'r Request<'_>) -> response::Result<'static> {
+        let mut body = json!({
+            "error": self.error,
+            "message": self.message,
+        });
+        if let Some(details) = self.details {
+            body["details"] = details;
+        }
+        (self.status, Json(body)).respond_to(r)
+    }
+}
+
+/// Internal errors are logged, but not shown to the client
+impl<T> From<T> for ApiError
+where
+    anyhow::Error: From<T>,
+{
+    fn from(value: T) -> Self {
+        let e = anyhow::Error::from(value);
+        rocket::error!("Internal error in API call: {:?}", e);
+        ApiError::new(
+            Status::InternalServerError,
+            "internal",
+            "Internal server error",
+        )
+    }
+}
+
+type ApiResult<T> = std::result::Result<Json<T>, ApiError>;
+
+fn parse_email(email: &str) -> Result<EmailAddress, ApiError> {
+    EmailAddress::new(email.to_owned()).map_err(|errors| {
+        let message = errors
+            .iter()
+            .map(|e| e.kind.to_string())
+            .collect::<Vec<_>>()
+            .join("; ");
+        ApiError::new(Status::UnprocessableEntity, "invalid_email", message)
+    })
+}
+
+/// `Operation`, but with readable names in JSON
+#[derive(Serialize, Deserialize, Copy, Clone)]
+#[serde(rename_all = "lowercase")]
+pub enum ApiOperation {
+    Add,
+    Remove,
+}
+
+impl From<ApiOperation> for Operation {
+    fn from(op: ApiOperation) -> Self {
+        match op {
+            ApiOperation::Add => Operation::Add,
+            ApiOperation::Remove => Operation::Remove,
+        }
+    }
+}
+
+#[get("/nodes")]
+async fn nodes(db: DbConn) -> ApiResult<Vec<NodeQuery>> {
+    use crate::schema::*;
+
+    let nodes = db
+        .run(|db| nodes::table.order_by(nodes::name).load::<NodeQuery>(db))
+        .await?;
+    Ok(Json(nodes))
+}
+
+#[get("/nodes/<id>")]
+async fn node(id: String, db: DbConn) -> ApiResult<NodeQuery> {
+    use crate::schema::*;
+
+    let node = db
+        .run(move |db| nodes::table.find(id).first::<NodeQuery>(db).optional())
+        .await?;
+    match node {
+        Some(node) => Ok(Json(node)),
+        None => Err(ApiError::new(
+            Status::NotFound,
+            "node_not_found",
+            "No node with this ID",
+        )),
+    }
+}
+
+#[derive(Serialize)]
+pub struct Subscription {
+    pub node: String,
+    /// Current state of the node, if it still exists
+    pub name: Option<String>,
+    pub online: Option<bool>,
+    pub created_at: Option<DateTime<Utc>>,
+    pub confirmed_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize)]
+pub struct Subscriptions {
+    pub email: EmailAddress,
+    pub subscriptions: Vec<Subscription>,
+}
+
+/// The nodes monitored by `email`.  If the list is protected, this needs a session, an API token,
+/// or an `access` token (as found in the list links we send).
+#[get("/subscriptions?<email>&<access>")]
+async fn subscriptions(
+    email: &str,
+    access: Option<&str>,
+    session: Option<Session>,
+    bearer: Bearer,
+    ctx: Ctx<'_>,
+    db: DbConn,
+) -> ApiResult<Subscriptions> {
+    use crate::schema::*;
+
+    let email = parse_email(email)?;
+    let logged_in = session.is_some_and(|session| session.is_for(&email)) || bearer.is_for(&email);
+    if ctx.config().ui.protect_list
+        && !logged_in
+        && !access.is_some_and(|access| check_list_access(ctx.config(), &email, access))
+    {
+        return Err(ApiError::new(
+            Status::Forbidden,
+            "access_denied",
+            "The list of monitored nodes is protected; log in or provide an (API) token",
+        ));
+    }
+
+    let subscriptions = db
+        .run({
+            let email = email.clone();
+            move |db| {
+                monitors::table
+                    .filter(monitors::email.eq(&*email))
+                    .left_join(nodes::table.on(monitors::id.eq(nodes::id)))
+                    .order_by(monitors::id)
+                    .load::<MonitorNodeQuery>(db)
+            }
+        })
+        .await?
+        .into_iter()
+        .map(|m| Subscription {
+            node: m.monitor.id,
+            name: m.node.as_ref().map(|node| node.name.clone()),
+            online: m.node.map(|node| node.online),
+            created_at: m.monitor.created_at,
+            confirmed_at: m.monitor.confirmed_at,
+        })
+        .collect();
+    Ok(Json(Subscriptions {
+        email,
+        subscriptions,
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct SubscriptionRequest {
+    email: String,
+    nodes: Vec<String>,
+    op: ApiOperation,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum SubscriptionResponse {
+    /// A confirmation email was sent, nothing changes until that is confirmed
+    ConfirmationSent,
+    /// The request was executed directly (because there is a session or API token for this
+    /// address)
+    Done { outcomes: Vec<NodeOutcome> },
+}
+
+/// Add or remove nodes.  Without a session or API token for the address, this sends a
+/// confirmation email.
+#[post("/subscriptions", format = "json", data = "<request>")]
+async fn change_subscriptions(
+    request: Result<Json<SubscriptionRequest>, json::Error<'_>>,
+    session: Option<Session>,
+    bearer: Bearer,
+    ctx: Ctx<'_>,
+    db: DbConn,
+    client_ip: Option<IpAddr>,
+) -> Result<(Status, Json<SubscriptionResponse>), ApiError> {
+    let request = request
+        .map_err(|e| ApiError::new(Status::BadRequest, "invalid_request", e.to_string()))?
+        .into_inner();
+    if request.nodes.is_empty() {
+        return Err(ApiError::new(
+            Status::UnprocessableEntity,
+            "no_nodes",
+            "At least one node is required",
+        ));
+    }
+    let mut action = Action {
+        nodes: request.nodes,
+        email: parse_email(&request.email)?,
+        op: request.op.into(),
+    };
+    action.normalize();
+
+    let (nodes, missing_nodes) = action_nodes(&action, &db).await?;
+    if !missing_nodes.is_empty() {
+        return Err(ApiError::new(
+            Status::UnprocessableEntity,
+            "unknown_nodes",
+            "Some of the nodes do not exist",
+        )
+        .with_details(json!({ "nodes": missing_nodes })));
+    }
+
+    let via = if bearer.is_for(&action.email) {
+        Some("api-token")
+    } else if session.is_some_and(|session| session.is_for(&action.email)) {
+        Some("session")
+    } else {
+        None
+    };
+    if let Some(via) = via {
+        // Requested and confirmed in one go
+        let now = Utc::now();
+        let confirmation = Confirmation {
+            requested_at: Some(now),
+            confirmed_at: now,
+            via,
+            client_ip_hash: hash_client_ip(&ctx.config().secrets, client_ip),
+        };
+        let outcomes = action.run(&db, confirmation).await?;
+        ctx.metrics().action(action.op);
+        return Ok((Status::Ok, Json(SubscriptionResponse::Done { outcomes })));
+    }
+
+    // Make sure we are not used to flood someone's inbox
+    if !ctx.rate_limit(&db, &action.email, client_ip).await? {
+        return Err(ApiError::new(
+            Status::TooManyRequests,
+            "rate_limited",
+            "Too many emails were requested, please try again later",
+        ));
+    }
+    send_confirmation(&ctx, &action, &nodes).await?;
+    Ok((
+        Status::Accepted,
+        Json(SubscriptionResponse::ConfirmationSent),
+    ))
+}
+
+#[catch(default)]
+fn default_catcher(status: Status, _: &Request<'_>) -> ApiError {
+    match status.code {
+        // This is what the `Bearer` guard fails with
+        401 => ApiError::new(status, "invalid_token", "The API token is not valid"),
+        _ => ApiError::new(status, "http_error", status.reason_lossy()),
+    }
+}
+
+pub fn routes() -> Vec<rocket::Route> {
+    routes![nodes, node, subscriptions, change_subscriptions]
+}
+
+pub fn catchers() -> Vec<rocket::Catcher> {
+    catchers![default_catcher]
+}
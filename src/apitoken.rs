@@ -0,0 +1,190 @@
+//  ff-node-monitor -- Monitoring for Freifunk nodes
+//  Copyright (C) 2018  Ralf Jung <post AT ralfj DOT de>
+//
+//  This program is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU Affero General Public License as published by
+//  the Free Software Foundation, either version 3 of the License, or
+//  (at your option) any later version.
+//
+//  This program is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//  GNU Affero General Public License for more details.
+//
+//  You should have received a copy of the GNU Affero General Public License
+//  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use anyhow::Result;
+use chrono::Utc;
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use rocket::http::Status;
+use rocket::request::{self, FromRequest, Outcome};
+use rocket::Request;
+
+use crate::action::Signable;
+use crate::config::{Config, Secrets};
+use crate::confirm::Confirmable;
+use crate::db::DbConn;
+use crate::email::EmailAddress;
+use crate::models::*;
+use crate::schema::*;
+use crate::util::{hash_personal_data, random_secret};
+
+/// Emailed to an address to create an API token for that address, until the given time
+#[derive(Serialize, Deserialize)]
+pub struct IssueApiToken {
+    pub email: EmailAddress,
+    /// Name chosen by the user to recognize the token later
+    pub label: String,
+    /// Random ID, so that each link creates at most one token
+    pub request_id: String,
+    /// Unix timestamp
+    pub valid_until: i64,
+}
+
+impl Signable for IssueApiToken {
+    const PURPOSE: &'static str = "api-token";
+}
+
+impl Confirmable for IssueApiToken {
+    const EMAIL_TEMPLATE: &'static str = "api_token_link";
+    const REQUESTED_TEMPLATE: &'static str = "api_token_requested";
+    const CONFIRM_TEMPLATE: &'static str = "api_token_confirm";
+
+    fn email(&self) -> &EmailAddress {
+        &self.email
+    }
+
+    fn valid_until(&self) -> i64 {
+        self.valid_until
+    }
+
+    fn template_vals(&self) -> serde_json::Value {
+        json!({
+            "email": self.email,
+            "label": self.label,
+        })
+    }
+}
+
+/// Create a new API token for `email`.  Returns the token; only its hash is stored.  If a
+/// `request_id` is given and a token was already created for it, nothing happens and `None` is
+/// returned.
+pub async fn create(
+    db: &DbConn,
+    secrets: &Secrets,
+    email: &EmailAddress,
+    label: &str,
+    request_id: Option<&str>,
+) -> Result<Option<String>> {
+    let token = random_secret()?;
+    let token_hash = hash_personal_data(secrets, &token);
+    let email = email.clone();
+    let label = label.trim().to_owned();
+    let request_id = request_id.map(str::to_owned);
+    let num_inserted = db
+        .run(move |db| {
+            diesel::insert_into(api_tokens::table)
+                .values(&ApiToken {
+                    email: &email,
+                    token_hash: &token_hash,
+                    label: &label,
+                    request_id: request_id.as_deref(),
+                })
+                .on_conflict_do_nothing()
+                .execute(db)
+        })
+        .await?;
+    Ok(Some(token).filter(|_| num_inserted > 0))
+}
+
+/// The API tokens of `email`, newest first
+pub async fn list(db: &DbConn, email: &EmailAddress) -> Result<Vec<ApiTokenQuery>> {
+    let email = email.clone();
+    Ok(db
+        .run(move |db| {
+            api_tokens::table
+                .filter(api_tokens::email.eq(&*email))
+                .order_by(api_tokens::id.desc())
+                .select((
+                    api_tokens::id,
+                    api_tokens::email,
+                    api_tokens::label,
+                    api_tokens::created_at,
+                    api_tokens::last_used_at,
+                ))
+                .load::<ApiTokenQuery>(db)
+        })
+        .await?)
+}
+
+/// Revoke the token with the given ID, if it belongs to `email`.  Returns whether it existed.
+pub async fn revoke(db: &DbConn, email: &EmailAddress, id: i64) -> Result<bool> {
+    let email = email.clone();
+    let num_deleted = db
+        .run(move |db| {
+            diesel::delete(
+                api_tokens::table
+                    .filter(api_tokens::id.eq(id))
+                    .filter(api_tokens::email.eq(&*email)),
+            )
+            .execute(db)
+        })
+        .await?;
+    Ok(num_deleted > 0)
+}
+
+/// A request guard for the `Authorization: Bearer <token>` header.  Requests without that header
+/// are let through (with `email` being `None`), but requests with an invalid token are rejected.
+pub struct Bearer {
+    pub email: Option<EmailAddress>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Bearer {
+    type Error = ();
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let config = request.rocket().state::<Config>().unwrap();
+        let token = match request.headers().get_one("Authorization") {
+            Some(header) => match header.strip_prefix("Bearer ") {
+                Some(token) => hash_personal_data(&config.secrets, token.trim()),
+                None => return Outcome::Error((Status::Unauthorized, ())),
+            },
+            None => return Outcome::Success(Bearer { email: None }),
+        };
+        let db = match request.guard::<DbConn>().await {
+            Outcome::Success(db) => db,
+            _ => return Outcome::Error((Status::ServiceUnavailable, ())),
+        };
+        let email = db
+            .run(move |db| {
+                diesel::update(api_tokens::table.filter(api_tokens::token_hash.eq(token)))
+                    .set(api_tokens::last_used_at.eq(Utc::now()))
+                    .returning(api_tokens::email)
+                    .get_result::<String>(db)
+                    .optional()
+            })
+            .await;
+        match email {
+            Ok(Some(email)) => match EmailAddress::new(email) {
+                Ok(email) => Outcome::Success(Bearer { email: Some(email) }),
+                Err(_) => Outcome::Error((Status::Unauthorized, ())),
+            },
+            Ok(None) => Outcome::Error((Status::Unauthorized, ())),
+            Err(e) => {
+                rocket::error!("Failed to check API token: {:?}", e);
+                Outcome::Error((Status::InternalServerError, ()))
+            }
+        }
+    }
+}
+
+impl Bearer {
+    /// Whether the token may act on behalf of `email`
+    pub fn is_for(&self, email: &EmailAddress) -> bool {
+        self.email.as_ref().is_some_and(|e| **e == **email)
+    }
+}
@@ -14,6 +14,8 @@
 //  You should have received a copy of the GNU Affero General Public License
 //  along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+use std::collections::{BTreeMap, HashMap};
+
 use rocket::fairing::{AdHoc, Fairing};
 use rocket::http::uri;
 
@@ -32,6 +34,9 @@ pub struct Ui {
     pub instance_article_dative: String,
     pub email_from: Address,
     pub min_online_nodes: Option<usize>,
+    /// Only show the list of monitored nodes via a link sent to that address
+    #[serde(default)]
+    pub protect_list: bool,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -51,8 +56,22 @@ impl Urls {
 #[derive(Deserialize)]
 pub struct Secrets {
     pub smtp_host: Option<String>,
-    #[serde(with = "util::hex_signing_key")]
-    pub action_signing_key: hmac::Key,
+    #[serde(with = "util::hex_signing_keys")]
+    action_signing_keys: HashMap<String, hmac::Key>,
+    action_signing_key_id: String,
+    /// Key for hashing personal data (like client IP addresses) before storing it
+    #[serde(with = "util::hex_key")]
+    personal_data_key: hmac::Key,
+    /// Token to log in to the admin pages; they are disabled if this is not set
+    pub admin_token: Option<String>,
+    /// Token required to access `/metrics`; it is public if this is not set
+    pub metrics_token: Option<String>,
+    /// Access token of the bot account sending Matrix notifications
+    pub matrix_access_token: Option<String>,
+    /// Token of the Telegram bot sending notifications
+    pub telegram_bot_token: Option<String>,
+    /// Telegram has to send this with every update it delivers to us
+    pub telegram_webhook_secret: Option<String>,
 }
 
 impl Secrets {
@@ -60,6 +79,108 @@ impl Secrets {
     pub fn get_smtp_host(&self) -> &str {
         self.smtp_host.as_deref().unwrap_or("localhost")
     }
+
+    /// The ID and key to use for signing new data
+    pub fn current_signing_key(&self) -> (&str, &hmac::Key) {
+        let id = self.action_signing_key_id.as_str();
+        (id, &self.action_signing_keys[id])
+    }
+
+    /// Look up the key with the given ID for verifying data
+    pub fn signing_key(&self, id: &str) -> Option<&hmac::Key> {
+        self.action_signing_keys.get(id)
+    }
+
+    pub fn personal_data_key(&self) -> &hmac::Key {
+        &self.personal_data_key
+    }
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone)]
+pub struct Limit {
+    /// Maximal number of emails...
+    pub max: i64,
+    /// ...within this many seconds
+    pub window: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct RateLimits {
+    pub per_email: Limit,
+    pub per_ip: Limit,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        RateLimits {
+            per_email: Limit {
+                max: 5,
+                window: 60 * 60,
+            },
+            per_ip: Limit {
+                max: 20,
+                window: 60 * 60,
+            },
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct Health {
+    /// `/readyz` fails if the last successful cron run is older than this many seconds
+    pub max_cron_age: u32,
+    /// Whether `/readyz` also checks that the SMTP host is reachable
+    pub check_smtp: bool,
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Health {
+            max_cron_age: 30 * 60,
+            check_smtp: false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Matrix {
+    /// Base URL of the homeserver of the bot account
+    pub homeserver: Url,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Telegram {
+    /// Username of the bot (without the `@`), for linking to it
+    pub bot_name: String,
+    /// Base URL of the Bot API
+    #[serde(default = "Telegram::default_api_url")]
+    pub api_url: Url,
+}
+
+impl Telegram {
+    fn default_api_url() -> Url {
+        Url::parse("https://api.telegram.org/").unwrap()
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Alertmanager {
+    /// Base URL of Alertmanager
+    pub url: Url,
+    /// How long (in seconds) an alert lasts if the node does not come back online
+    #[serde(default = "Alertmanager::default_alert_lifetime")]
+    pub alert_lifetime: u32,
+    /// Labels added to all alerts
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+}
+
+impl Alertmanager {
+    fn default_alert_lifetime() -> u32 {
+        7 * 24 * 60 * 60
+    }
 }
 
 #[derive(Deserialize)]
@@ -67,6 +188,16 @@ pub struct Config {
     pub ui: Ui,
     pub secrets: Secrets,
     pub urls: Urls,
+    #[serde(default)]
+    pub rate_limits: RateLimits,
+    #[serde(default)]
+    pub health: Health,
+    /// Matrix notifications are disabled if this is not set
+    pub matrix: Option<Matrix>,
+    /// Telegram notifications are disabled if this is not set
+    pub telegram: Option<Telegram>,
+    /// Alerts are not sent to Alertmanager if this is not set
+    pub alertmanager: Option<Alertmanager>,
 }
 
 pub fn fairing(section: &'static str) -> impl Fairing {
@@ -76,6 +207,25 @@ pub fn fairing(section: &'static str) -> impl Fairing {
             let config: Config = rocket.figment().extract_inner(section).unwrap_or_else(|_| {
                 panic!("[{}] table in Rocket.toml missing or not a table", section)
             });
+            if config
+                .secrets
+                .signing_key(&config.secrets.action_signing_key_id)
+                .is_none()
+            {
+                panic!(
+                    "action_signing_key_id {:?} does not refer to any of the action_signing_keys",
+                    config.secrets.action_signing_key_id
+                );
+            }
+            if config.matrix.is_some() && config.secrets.matrix_access_token.is_none() {
+                panic!("[matrix] is configured, but matrix_access_token is missing");
+            }
+            if config.telegram.is_some()
+                && (config.secrets.telegram_bot_token.is_none()
+                    || config.secrets.telegram_webhook_secret.is_none())
+            {
+                panic!("[telegram] is configured, but telegram_bot_token or telegram_webhook_secret is missing");
+            }
             rocket.manage(config)
         },
     )
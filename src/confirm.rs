@@ -0,0 +1,170 @@
+//  ff-node-monitor -- Monitoring for Freifunk nodes
+//  Copyright (C) 2018  Ralf Jung <post AT ralfj DOT de>
+//
+//  This program is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU Affero General Public License as published by
+//  the Free Software Foundation, either version 3 of the License, or
+//  (at your option) any later version.
+//
+//  This program is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//  GNU Affero General Public License for more details.
+//
+//  You should have received a copy of the GNU Affero General Public License
+//  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::net::IpAddr;
+
+use anyhow::Result;
+use chrono::Utc;
+use rocket::http::uri::Origin;
+use rocket_dyn_templates::Template;
+use serde_json::json;
+
+use crate::action::{Signable, Signed};
+use crate::config::Secrets;
+use crate::db::DbConn;
+use crate::email::EmailAddress;
+use crate::routes;
+use crate::util::Ctx;
+
+/// Something a user asks for that has to be confirmed by email (unless they are logged in).  It
+/// gets signed and emailed as a link.  Following the link only shows a form, since mail scanners
+/// like to follow links in emails; submitting that form carries out the request.
+pub trait Confirmable: Signable {
+    /// The email with the link
+    const EMAIL_TEMPLATE: &'static str;
+    /// The page shown after sending the email
+    const REQUESTED_TEMPLATE: &'static str;
+    /// The page behind the link, with the form to confirm
+    const CONFIRM_TEMPLATE: &'static str;
+
+    /// The address that has to confirm
+    fn email(&self) -> &EmailAddress;
+    /// Unix timestamp until which the link can be used
+    fn valid_until(&self) -> i64;
+    /// What the templates need to know about the request (a JSON object)
+    fn template_vals(&self) -> serde_json::Value;
+}
+
+fn check<T: Confirmable>(secrets: &Secrets, token: &str) -> Option<T> {
+    Signed::<T>::decode(token)
+        .ok()
+        .and_then(|request| request.verify(secrets).ok())
+        .filter(|request| request.valid_until() > Utc::now().timestamp())
+}
+
+impl<'r> Ctx<'r> {
+    /// Email a link to confirm `request` to its address.  `confirm_uri` gives the route showing
+    /// the confirmation form for a token.  Returns the page to show.
+    pub async fn request_confirmation<T: Confirmable>(
+        &self,
+        db: &DbConn,
+        client_ip: Option<IpAddr>,
+        request: T,
+        confirm_uri: impl FnOnce(&str) -> Origin<'static>,
+    ) -> Result<Template> {
+        let email = request.email().clone();
+        let list_url = routes::list_url(self.config(), &email, false);
+
+        // Make sure we are not used to flood someone's inbox
+        if !self.rate_limit(db, &email, client_ip).await? {
+            return self.template(
+                "rate_limited",
+                json!({
+                    "email": email,
+                    "list_url": list_url,
+                }),
+            );
+        }
+
+        // Build and send email
+        let mut vals = request.template_vals();
+        let token = Signed::sign(request, &self.config().secrets).encode();
+        let vals_obj = vals.as_object_mut().unwrap();
+        vals_obj.insert(
+            "confirm_url".to_owned(),
+            json!(self.config().urls.absolute(confirm_uri(&token))),
+        );
+        vals_obj.insert(
+            "unsubscribe_url".to_owned(),
+            json!(routes::unsubscribe_url(self.config(), &email)),
+        );
+        self.email(T::EMAIL_TEMPLATE, vals, &email).await?;
+
+        // Render
+        self.template(
+            T::REQUESTED_TEMPLATE,
+            json!({
+                "email": email,
+                "list_url": list_url,
+            }),
+        )
+    }
+
+    /// The request behind a confirmation link, if the link is valid and has not expired
+    pub fn check_confirmation<T: Confirmable>(&self, token: &str) -> Option<T> {
+        check(&self.config().secrets, token)
+    }
+
+    /// The page behind a confirmation link: a form to confirm the request
+    pub fn confirmation_form<T: Confirmable>(&self, token: String) -> Result<Template> {
+        match self.check_confirmation::<T>(&token) {
+            Some(request) => {
+                let mut vals = request.template_vals();
+                vals.as_object_mut()
+                    .unwrap()
+                    .insert("token".to_owned(), json!(token));
+                self.template(T::CONFIRM_TEMPLATE, vals)
+            }
+            None => self.template("run_action_error", json!({})),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::webhook::AddWebhook;
+
+    fn secrets() -> Secrets {
+        serde_json::from_value(json!({
+            "action_signing_keys": { "1": "00112233" },
+            "action_signing_key_id": "1",
+            "personal_data_key": "00",
+        }))
+        .unwrap()
+    }
+
+    fn add_webhook(valid_until: i64) -> String {
+        let add = AddWebhook {
+            email: EmailAddress::new("a@example.org".to_owned()).unwrap(),
+            url: "https://example.org/hook".to_owned(),
+            valid_until,
+        };
+        Signed::sign(add, &secrets()).encode()
+    }
+
+    #[test]
+    fn accept_valid_link() {
+        let token = add_webhook(Utc::now().timestamp() + 60);
+        let add = check::<AddWebhook>(&secrets(), &token).unwrap();
+        assert_eq!(add.url, "https://example.org/hook");
+    }
+
+    #[test]
+    fn reject_expired_link() {
+        let token = add_webhook(Utc::now().timestamp() - 1);
+        assert!(check::<AddWebhook>(&secrets(), &token).is_none());
+    }
+
+    #[test]
+    fn reject_garbage() {
+        assert!(check::<AddWebhook>(&secrets(), "").is_none());
+        assert!(check::<AddWebhook>(&secrets(), "not a token").is_none());
+        let mut token = add_webhook(Utc::now().timestamp() + 60);
+        token.pop();
+        assert!(check::<AddWebhook>(&secrets(), &token).is_none());
+    }
+}
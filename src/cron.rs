@@ -17,14 +17,12 @@
 use std::collections::HashMap;
 
 use anyhow::{bail, Result};
+use chrono::{DateTime, Utc};
 use diesel::prelude::*;
-use serde_json::{self, json};
-
-use rocket::uri;
 
 use crate::db::DbConn;
-use crate::email::EmailAddress;
 use crate::models;
+use crate::notify;
 use crate::routes;
 use crate::schema::*;
 use crate::util::Ctx;
@@ -33,10 +31,17 @@ mod json {
     use chrono::{DateTime, Utc};
     use serde::Deserialize;
 
+    #[derive(Deserialize, Debug)]
+    pub(crate) struct System {
+        pub(crate) site_code: Option<String>,
+        pub(crate) domain_code: Option<String>,
+    }
+
     #[derive(Deserialize, Debug)]
     pub(crate) struct NodeInfo {
         pub(crate) node_id: Option<String>,
         pub(crate) hostname: Option<String>,
+        pub(crate) system: Option<System>,
     }
 
     #[derive(Deserialize, Debug)]
@@ -45,15 +50,14 @@ mod json {
     }
 
     #[derive(Deserialize, Debug)]
-    #[allow(unused)] // we don't actually need all these fields
     pub(crate) struct Statistics {
         pub(crate) memory_usage: Option<f64>,
         pub(crate) rootfs_usage: Option<f64>,
         pub(crate) loadavg: Option<f64>,
+        pub(crate) clients: Option<i32>,
     }
 
     #[derive(Deserialize, Debug)]
-    #[allow(unused)] // we don't actually need all these fields
     pub(crate) struct Node {
         pub(crate) nodeinfo: NodeInfo,
         pub(crate) flags: Flags,
@@ -72,10 +76,17 @@ mod json {
 }
 
 // Just the data about the node (the RHS of the HashMap)
-#[derive(Clone, PartialEq, Eq, serde::Deserialize)]
+#[derive(Clone, PartialEq)]
 struct NodeData {
     name: String,
     online: bool,
+    first_seen: Option<DateTime<Utc>>,
+    last_seen: Option<DateTime<Utc>>,
+    clients: Option<i32>,
+    loadavg: Option<f64>,
+    memory_usage: Option<f64>,
+    rootfs_usage: Option<f64>,
+    domain: Option<String>,
 }
 
 // From a JSON node, extract node ID and other information
@@ -83,6 +94,17 @@ fn json_to_node_data(node: json::Node) -> Option<(String, NodeData)> {
     let node_data = NodeData {
         name: node.nodeinfo.hostname?,
         online: node.flags.online,
+        first_seen: Some(node.firstseen),
+        last_seen: Some(node.lastseen),
+        clients: node.statistics.clients,
+        loadavg: node.statistics.loadavg,
+        memory_usage: node.statistics.memory_usage,
+        rootfs_usage: node.statistics.rootfs_usage,
+        // Nodes of communities without multiple domains just have a site
+        domain: node
+            .nodeinfo
+            .system
+            .and_then(|system| system.domain_code.or(system.site_code)),
     };
     Some((node.nodeinfo.node_id?, node_data))
 }
@@ -91,6 +113,13 @@ fn model_to_node_data(node: models::NodeQuery) -> (String, NodeData) {
     let node_data = NodeData {
         name: node.name,
         online: node.online,
+        first_seen: node.first_seen,
+        last_seen: node.last_seen,
+        clients: node.clients,
+        loadavg: node.loadavg,
+        memory_usage: node.memory_usage,
+        rootfs_usage: node.rootfs_usage,
+        domain: node.domain,
     };
     (node.id, node_data)
 }
@@ -101,6 +130,13 @@ impl NodeData {
             id,
             name: self.name,
             online: self.online,
+            first_seen: self.first_seen,
+            last_seen: self.last_seen,
+            clients: self.clients,
+            loadavg: self.loadavg,
+            memory_usage: self.memory_usage,
+            rootfs_usage: self.rootfs_usage,
+            domain: self.domain,
         }
     }
 }
@@ -111,14 +147,64 @@ pub enum UpdateResult {
     NotEnoughOnline(usize),
 }
 
+/// How long (in days) to remember cron runs
+const CRON_RUN_RETENTION_DAYS: i64 = 30;
+
+/// A short name for the outcome of a cron run
+pub fn outcome(result: &Result<UpdateResult>) -> &'static str {
+    match result {
+        Ok(UpdateResult::AllOk) => "ok",
+        Ok(UpdateResult::NotEnoughOnline(_)) => "not_enough_online",
+        Err(_) => "error",
+    }
+}
+
+/// Remember the result of a cron run for the status page
+pub async fn record_run(db: &DbConn, result: &Result<UpdateResult>) -> Result<()> {
+    let outcome = outcome(result);
+    let (online_nodes, message) = match result {
+        Ok(UpdateResult::AllOk) => (None, None),
+        Ok(UpdateResult::NotEnoughOnline(online)) => (Some(*online as i32), None),
+        Err(e) => (None, Some(format!("{:#}", e))),
+    };
+    db.run_transaction(move |db| {
+        diesel::delete(cron_runs::table.filter(
+            cron_runs::created_at.lt(Utc::now() - chrono::Duration::days(CRON_RUN_RETENTION_DAYS)),
+        ))
+        .execute(db)?;
+        diesel::insert_into(cron_runs::table)
+            .values(&models::CronRun {
+                outcome,
+                online_nodes,
+                message: message.as_deref(),
+            })
+            .execute(db)?;
+        Ok(())
+    })
+    .await
+}
+
 /// Fetch the latest nodelist, update node state and send out emails
 impl<'r> Ctx<'r> {
-    pub async fn update_nodes(&self, db: &DbConn) -> Result<UpdateResult> {
-        let config = self.config();
-        let cur_nodes: json::Nodes = reqwest::get(config.urls.nodes.clone())
+    async fn fetch_nodes(&self) -> Result<json::Nodes> {
+        Ok(reqwest::get(self.config().urls.nodes.clone())
             .await?
+            .error_for_status()?
             .json()
-            .await?;
+            .await?)
+    }
+
+    pub async fn update_nodes(&self, db: &DbConn) -> Result<UpdateResult> {
+        let config = self.config();
+        let cur_nodes: json::Nodes = match self.fetch_nodes().await {
+            Ok(cur_nodes) => cur_nodes,
+            Err(e) => {
+                self.metrics().feed_error();
+                return Err(e);
+            }
+        };
+        // All changes in this run count as seen when we got the node list
+        let observed_at = Utc::now();
 
         if cur_nodes.version != 2 {
             bail!(
@@ -160,6 +246,13 @@ impl<'r> Ctx<'r> {
                                     .set((
                                         nodes::name.eq(cur_data.name.as_str()),
                                         nodes::online.eq(cur_data.online),
+                                        nodes::first_seen.eq(cur_data.first_seen),
+                                        nodes::last_seen.eq(cur_data.last_seen),
+                                        nodes::clients.eq(cur_data.clients),
+                                        nodes::loadavg.eq(cur_data.loadavg),
+                                        nodes::memory_usage.eq(cur_data.memory_usage),
+                                        nodes::rootfs_usage.eq(cur_data.rootfs_usage),
+                                        nodes::domain.eq(cur_data.domain.as_deref()),
                                     ))
                                     .execute(db)?;
                             }
@@ -191,6 +284,13 @@ impl<'r> Ctx<'r> {
                                 id: id.as_str(),
                                 name: cur_data.name.as_str(),
                                 online: cur_data.online,
+                                first_seen: cur_data.first_seen,
+                                last_seen: cur_data.last_seen,
+                                clients: cur_data.clients,
+                                loadavg: cur_data.loadavg,
+                                memory_usage: cur_data.memory_usage,
+                                rootfs_usage: cur_data.rootfs_usage,
+                                domain: cur_data.domain.as_deref(),
                             })
                             .execute(db)?;
                         if cur_data.online {
@@ -200,43 +300,58 @@ impl<'r> Ctx<'r> {
                         }
                     }
 
+                    // Remember the changes for the history of each node
+                    let events: Vec<models::NodeEvent> = changed
+                        .iter()
+                        .map(|(id, data)| models::NodeEvent {
+                            node: id,
+                            online: data.online,
+                            created_at: observed_at,
+                        })
+                        .collect();
+                    diesel::insert_into(node_events::table)
+                        .values(&events)
+                        .execute(db)?;
+
                     Ok(changed)
                 }
             })
             .await?;
 
         // Send out notifications (not in the transaction as we don't really care here -- also
-        // we have an external side-effect, the email, which we cannot roll back anyway)
+        // we have external side-effects like emails, which we cannot roll back anyway)
+        let notifiers = notify::notifiers(config)?;
+        let mut changed_nodes = Vec::new();
         for (id, cur_data) in changed.into_iter() {
             // See who monitors this node
-            let watchers = db
+            let subscribers = db
                 .run({
                     let id = id.clone();
                     move |db| {
                         monitors::table
                             .filter(monitors::id.eq(id.as_str()))
-                            .load::<models::MonitorQuery>(db)
+                            .select(monitors::email)
+                            .load::<String>(db)
                     }
                 })
                 .await?;
-            // Send them email
-            let node = cur_data.into_model(id);
-            for watcher in watchers.iter() {
-                // Generate email text
-                let email = EmailAddress::new(watcher.email.clone()).unwrap();
-                let list_url = config.urls.absolute(uri!(routes::list(email = &email)));
-                // Build and send email
-                self.email(
-                    "notification",
-                    json!({
-                        "node": node,
-                        "list_url": list_url.as_str(),
-                    }),
-                    watcher.email.as_str(),
-                )
-                .await?;
-            }
+            changed_nodes.push((cur_data.into_model(id), subscribers));
         }
+        let events: Vec<(notify::Event, Vec<String>)> = changed_nodes
+            .iter()
+            .map(|(node, subscribers)| {
+                let event = notify::Event {
+                    node_url: routes::node_url(config, &node.id),
+                    // We only get here for nodes that changed their state
+                    was_online: !node.online,
+                    timestamp: observed_at,
+                    node,
+                };
+                (event, subscribers.clone())
+            })
+            .collect();
+        let failures = notify::dispatch(self, db, &notifiers, &events).await;
+        self.metrics().notification_errors(failures);
 
         Ok(UpdateResult::AllOk)
     }
@@ -1,10 +1,15 @@
 use anyhow::Result;
+use diesel::prelude::*;
 use diesel::{Connection, PgConnection};
 use diesel_migrations::MigrationHarness;
 
 use rocket::fairing::{AdHoc, Fairing};
 use rocket_sync_db_pools::{database, diesel};
 
+use crate::action;
+use crate::email::EmailAddress;
+use crate::schema::*;
+
 // DB connection guard type
 #[database("postgres")]
 pub struct DbConn(diesel::PgConnection);
@@ -18,12 +23,57 @@ pub fn migration() -> impl Fairing {
             .expect("could not connect to DB for migrations");
         conn.run(move |db| {
             db.run_pending_migrations(migrations).unwrap();
+            db.transaction(normalize_emails)
+                .expect("could not normalize email addresses");
         })
         .await;
         rocket
     })
 }
 
+/// Bring all email addresses of monitors into the normal form computed by `EmailAddress::new`,
+/// merging duplicates.  Addresses that got stored before that normal form was introduced are fixed
+/// up here.  Monitors of addresses that are not valid at all are moved to `invalid_monitors`, as
+/// we cannot send emails there.
+fn normalize_emails(db: &mut PgConnection) -> QueryResult<()> {
+    let emails: Vec<String> = monitors::table
+        .select(monitors::email)
+        .distinct()
+        .load(db)?;
+    for email in emails.into_iter() {
+        match EmailAddress::new(email.clone()) {
+            Ok(normalized) if *normalized == email => {}
+            Ok(normalized) => {
+                rocket::info!("Moving monitors of {:?} to {:?}", email, &*normalized);
+                action::move_monitors(db, &email, &normalized, None)?;
+            }
+            Err(_) => {
+                rocket::warn!("Moving monitors of invalid address {:?} aside", email);
+                let invalid = monitors::table.filter(monitors::email.eq(&email));
+                diesel::insert_into(invalid_monitors::table)
+                    .values(invalid.select((
+                        monitors::id,
+                        monitors::email,
+                        monitors::created_at,
+                        monitors::confirmed_at,
+                        monitors::confirmed_via,
+                    )))
+                    .into_columns((
+                        invalid_monitors::id,
+                        invalid_monitors::email,
+                        invalid_monitors::created_at,
+                        invalid_monitors::confirmed_at,
+                        invalid_monitors::confirmed_via,
+                    ))
+                    .on_conflict_do_nothing()
+                    .execute(db)?;
+                diesel::delete(invalid).execute(db)?;
+            }
+        }
+    }
+    Ok(())
+}
+
 impl DbConn {
     pub async fn run_transaction<T>(
         &self,
@@ -35,3 +85,98 @@ impl DbConn {
         self.run(move |db| db.transaction(f)).await
     }
 }
+
+/// The database for tests, from `TEST_DATABASE_URL`.  Tests that need it are marked `#[ignore]`,
+/// so they only run with `cargo test -- --include-ignored`.
+#[cfg(test)]
+fn test_database_url() -> String {
+    std::env::var("TEST_DATABASE_URL").expect("TEST_DATABASE_URL must be set for this test")
+}
+
+/// A connection to the database for tests, with all migrations applied
+#[cfg(test)]
+pub fn test_connection() -> PgConnection {
+    static MIGRATIONS: std::sync::Mutex<bool> = std::sync::Mutex::new(false);
+
+    let url = test_database_url();
+    let mut db = PgConnection::establish(&url).expect("could not connect to test DB");
+    let mut migrated = MIGRATIONS.lock().unwrap();
+    if !*migrated {
+        let migrations = diesel_migrations::FileBasedMigrations::find_migrations_directory()
+            .expect("could not load migrations");
+        db.run_pending_migrations(migrations).unwrap();
+        *migrated = true;
+    }
+    db
+}
+
+/// A Rocket configuration using the test database
+#[cfg(test)]
+pub fn test_figment() -> rocket::figment::Figment {
+    // Also makes sure the migrations ran
+    test_connection();
+    rocket::Config::figment().merge(("databases.postgres.url", test_database_url()))
+}
+
+/// A rocket with a connection pool to the test database
+#[cfg(test)]
+pub async fn test_rocket() -> rocket::local::asynchronous::Client {
+    let rocket = rocket::custom(test_figment()).attach(DbConn::fairing());
+    rocket::local::asynchronous::Client::untracked(rocket)
+        .await
+        .expect("could not start test rocket")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::random_secret;
+
+    fn monitors_of(db: &mut PgConnection, email: &str) -> Vec<String> {
+        monitors::table
+            .filter(monitors::email.eq(email))
+            .order_by(monitors::id)
+            .select(monitors::id)
+            .load(db)
+            .unwrap()
+    }
+
+    fn add_monitor(db: &mut PgConnection, id: &str, email: &str) {
+        diesel::insert_into(monitors::table)
+            .values((monitors::id.eq(id), monitors::email.eq(email)))
+            .execute(db)
+            .unwrap();
+    }
+
+    #[test]
+    #[ignore = "needs a PostgreSQL database in TEST_DATABASE_URL"]
+    fn normalize_and_merge() {
+        let mut db = test_connection();
+        let user = format!("normalize-{}", random_secret().unwrap());
+        let upper = format!("{}@Example.ORG", user);
+        let lower = format!("{}@example.org", user);
+        let invalid = format!("{}@@example.org", user);
+        add_monitor(&mut db, "n1", &upper);
+        add_monitor(&mut db, "n2", &upper);
+        add_monitor(&mut db, "n2", &lower);
+        add_monitor(&mut db, "n3", &invalid);
+
+        db.transaction(normalize_emails).unwrap();
+        assert!(monitors_of(&mut db, &upper).is_empty());
+        assert_eq!(monitors_of(&mut db, &lower), ["n1", "n2"]);
+        assert!(monitors_of(&mut db, &invalid).is_empty());
+        let moved_aside: Vec<String> = invalid_monitors::table
+            .filter(invalid_monitors::email.eq(&invalid))
+            .select(invalid_monitors::id)
+            .load(&mut db)
+            .unwrap();
+        assert_eq!(moved_aside, ["n3"]);
+
+        diesel::delete(monitors::table.filter(monitors::email.eq(&lower)))
+            .execute(&mut db)
+            .unwrap();
+        diesel::delete(invalid_monitors::table.filter(invalid_monitors::email.eq(&invalid)))
+            .execute(&mut db)
+            .unwrap();
+    }
+}
@@ -6,6 +6,7 @@ use lettre::{
     Address, AsyncSmtpTransport, AsyncTransport as _, Message, Tokio1Executor,
 };
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 
 use rocket::{
     form::{self, FromFormField},
@@ -14,6 +15,9 @@ use rocket::{
 use rocket_dyn_templates::Template;
 
 use crate::config::Config;
+use crate::db::DbConn;
+use crate::notify::{Event, Notifier};
+use crate::routes;
 use crate::util::Ctx;
 
 /// Type for email addresses in Rocket forms
@@ -21,18 +25,23 @@ use crate::util::Ctx;
 pub struct EmailAddress(String);
 
 impl EmailAddress {
+    /// Validate and normalize an email address: we accept exactly what we can send emails to,
+    /// and use the ASCII form of the domain so that each address has only one representation.
     pub fn new<'e>(s: String) -> form::Result<'e, EmailAddress> {
-        let email_parts: Vec<&str> = s.split('@').collect();
-        if email_parts.len() != 2 {
-            return Err(rocket::form::Error::validation("invalid credit card number").into());
-        }
-        if email_parts[0].is_empty() {
-            return Err(rocket::form::Error::validation("User part is empty").into());
-        }
-        if email_parts[1].find('.').is_none() {
-            return Err(rocket::form::Error::validation("Domain part must contain .").into());
+        let address = Address::from_str(s.trim())
+            .map_err(|e| form::Error::validation(format!("Invalid email address: {}", e)))?;
+        let domain = address.domain();
+        let domain = if domain.starts_with('[') {
+            // IP address literal
+            domain.to_owned()
+        } else {
+            idna::domain_to_ascii(domain)
+                .map_err(|_| form::Error::validation("Domain part is not a valid domain"))?
+        };
+        if domain.find('.').is_none() {
+            return Err(form::Error::validation("Domain part must contain .").into());
         }
-        Ok(EmailAddress(s))
+        Ok(EmailAddress(format!("{}@{}", address.user(), domain)))
     }
 }
 
@@ -59,6 +68,17 @@ impl<'r> Ctx<'r> {
         email_template: &'static str,
         vals: serde_json::Value,
         to: &str,
+    ) -> Result<()> {
+        let result = self.send_email(email_template, vals, to).await;
+        self.metrics().email(email_template, result.is_ok());
+        result
+    }
+
+    async fn send_email(
+        &self,
+        email_template: &'static str,
+        vals: serde_json::Value,
+        to: &str,
     ) -> Result<()> {
         let config = self.state::<Config>().unwrap();
         let email_text = Template::show(self, email_template, config.template_vals(vals)?).unwrap();
@@ -79,15 +99,7 @@ impl<'r> Ctx<'r> {
             .unwrap();
 
         // Send email
-        let smtp_host = config.secrets.get_smtp_host();
-        let mailer = if smtp_host == "localhost" {
-            AsyncSmtpTransport::unencrypted_localhost() // always port 25
-        } else {
-            AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(smtp_host)?
-                .port(25)
-                .build()
-        };
-        let r = mailer.send(message).await?;
+        let r = mailer(config)?.send(message).await?;
         if !r.is_positive() {
             bail!(
                 "sending email failed:\n{}",
@@ -97,3 +109,119 @@ impl<'r> Ctx<'r> {
         Ok(())
     }
 }
+
+/// The transport to use for sending emails
+pub fn mailer(config: &Config) -> Result<AsyncSmtpTransport<Tokio1Executor>> {
+    let smtp_host = config.secrets.get_smtp_host();
+    Ok(if smtp_host == "localhost" {
+        AsyncSmtpTransport::unencrypted_localhost() // always port 25
+    } else {
+        AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(smtp_host)?
+            .port(25)
+            .build()
+    })
+}
+
+/// Notifications by email, to every subscriber
+pub struct EmailNotifier;
+
+#[rocket::async_trait]
+impl Notifier for EmailNotifier {
+    async fn notify(
+        &self,
+        ctx: &Ctx<'_>,
+        _db: &DbConn,
+        event: &Event<'_>,
+        subscribers: &[String],
+    ) -> Result<()> {
+        let config = ctx.config();
+        // One bad address must not keep the other subscribers from getting their email
+        let mut failures = 0;
+        for subscriber in subscribers {
+            let email = match EmailAddress::new(subscriber.clone()) {
+                Ok(email) => email,
+                Err(e) => {
+                    rocket::error!("Not notifying invalid address {:?}: {}", subscriber, e);
+                    failures += 1;
+                    continue;
+                }
+            };
+            // Generate email text
+            let list_url = routes::list_url(config, &email, true);
+            let unsubscribe_url = routes::unsubscribe_url(config, &email);
+            // Build and send email
+            let result = ctx
+                .email(
+                    "notification",
+                    json!({
+                        "node": event.node,
+                        "node_url": event.node_url,
+                        "list_url": list_url,
+                        "unsubscribe_url": unsubscribe_url,
+                    }),
+                    &email,
+                )
+                .await;
+            if let Err(e) = result {
+                rocket::error!("Notifying {} failed: {:#}", &*email, e);
+                failures += 1;
+            }
+        }
+        if failures > 0 {
+            bail!(
+                "sending email to {} of {} subscribers failed",
+                failures,
+                subscribers.len()
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn normalize(s: &str) -> Option<String> {
+        EmailAddress::new(s.to_owned()).ok().map(|email| email.0)
+    }
+
+    #[test]
+    fn normalizes_domain() {
+        assert_eq!(
+            normalize("User@Example.ORG").as_deref(),
+            Some("User@example.org")
+        );
+        assert_eq!(
+            normalize("  user@example.org\n").as_deref(),
+            Some("user@example.org")
+        );
+        assert_eq!(
+            normalize("user@bücher.example").as_deref(),
+            Some("user@xn--bcher-kva.example")
+        );
+        // Already normalized addresses stay the same
+        assert_eq!(
+            normalize("user@xn--bcher-kva.example").as_deref(),
+            Some("user@xn--bcher-kva.example")
+        );
+    }
+
+    #[test]
+    fn keeps_ip_literals() {
+        assert_eq!(
+            normalize("user@[192.0.2.1]").as_deref(),
+            Some("user@[192.0.2.1]")
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_addresses() {
+        assert_eq!(normalize("user"), None);
+        assert_eq!(normalize("user@"), None);
+        assert_eq!(normalize("@example.org"), None);
+        assert_eq!(normalize("user@localhost"), None);
+        assert_eq!(normalize("us er@example.org"), None);
+        assert_eq!(normalize("user@exa mple.org"), None);
+    }
+}
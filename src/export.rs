@@ -0,0 +1,290 @@
+//  ff-node-monitor -- Monitoring for Freifunk nodes
+//  Copyright (C) 2018  Ralf Jung <post AT ralfj DOT de>
+//
+//  This program is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU Affero General Public License as published by
+//  the Free Software Foundation, either version 3 of the License, or
+//  (at your option) any later version.
+//
+//  This program is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//  GNU Affero General Public License for more details.
+//
+//  You should have received a copy of the GNU Affero General Public License
+//  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use serde::Serialize;
+
+use crate::config::Secrets;
+use crate::db::DbConn;
+use crate::email::EmailAddress;
+use crate::models::*;
+use crate::schema::*;
+use crate::util;
+
+#[derive(Serialize)]
+pub struct MonitorExport {
+    pub node: String,
+    /// Name of the node, if it still exists
+    pub node_name: Option<String>,
+    pub created_at: Option<DateTime<Utc>>,
+    pub confirmed_at: Option<DateTime<Utc>>,
+    pub confirmed_via: Option<String>,
+}
+
+/// An entry of the audit log: a node was added or removed
+#[derive(Serialize)]
+pub struct AuditExport {
+    pub at: DateTime<Utc>,
+    pub node: String,
+    pub action: String,
+    pub via: String,
+    /// Keyed hash of the IP address of the client that confirmed this
+    pub client_ip_hash: Option<String>,
+}
+
+/// An API token; the token itself is not stored
+#[derive(Serialize)]
+pub struct ApiTokenExport {
+    pub label: String,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+/// A webhook; the secret is not included
+#[derive(Serialize)]
+pub struct WebhookExport {
+    pub url: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/// A push target; the token is not included
+#[derive(Serialize)]
+pub struct PushTargetExport {
+    pub kind: String,
+    pub url: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/// A push target waiting for email confirmation; the token is not included
+#[derive(Serialize)]
+pub struct PendingPushTargetExport {
+    pub kind: String,
+    pub url: String,
+    pub valid_until: DateTime<Utc>,
+}
+
+/// A Matrix user or room that is notified
+#[derive(Serialize)]
+pub struct MatrixRoomExport {
+    pub target: String,
+    pub room_id: String,
+    pub created_at: DateTime<Utc>,
+    /// Whether the code we sent to the room was not entered yet
+    pub pending: bool,
+}
+
+/// A Telegram chat that is notified
+#[derive(Serialize)]
+pub struct TelegramChatExport {
+    pub chat_id: i64,
+    pub created_at: DateTime<Utc>,
+}
+
+/// A link to connect a Telegram chat that has not been used yet; only the hash of its token is
+/// stored
+#[derive(Serialize)]
+pub struct TelegramLinkExport {
+    pub token_hash: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Everything we store about an email address.  The secrets of webhooks and the tokens of push
+/// targets are not included; they are only used to send notifications.  Neither is the feed
+/// secret, as it is part of the feed URL shown on the list.  Rate-limiting entries for client IP
+/// addresses are left out because we cannot tell which address they belong to.  Notifications
+/// themselves are not stored at all.
+#[derive(Serialize)]
+pub struct DataExport {
+    pub email: EmailAddress,
+    pub exported_at: DateTime<Utc>,
+    pub monitors: Vec<MonitorExport>,
+    /// When we sent confirmation or login emails to this address.  We only keep these for as long
+    /// as they are needed for rate-limiting.
+    pub emails_sent: Vec<DateTime<Utc>>,
+    /// When nodes were added or removed for this address
+    pub audit_log: Vec<AuditExport>,
+    pub api_tokens: Vec<ApiTokenExport>,
+    pub webhooks: Vec<WebhookExport>,
+    pub push_targets: Vec<PushTargetExport>,
+    pub pending_push_targets: Vec<PendingPushTargetExport>,
+    pub matrix_rooms: Vec<MatrixRoomExport>,
+    pub telegram_chats: Vec<TelegramChatExport>,
+    pub telegram_links: Vec<TelegramLinkExport>,
+    /// When all logins of this address were last ended
+    pub sessions_reset_at: Option<DateTime<Utc>>,
+    /// When the current feed URL was created
+    pub feed_created_at: Option<DateTime<Utc>>,
+}
+
+impl DataExport {
+    pub async fn collect(
+        db: &DbConn,
+        secrets: &Secrets,
+        email: EmailAddress,
+    ) -> Result<DataExport> {
+        let email_hash = util::hash_personal_data(secrets, &email);
+        db.run_transaction(move |db| {
+            let monitors = monitors::table
+                .filter(monitors::email.eq(&*email))
+                .left_join(nodes::table.on(monitors::id.eq(nodes::id)))
+                .order_by(monitors::id)
+                .load::<MonitorNodeQuery>(db)?
+                .into_iter()
+                .map(|m| MonitorExport {
+                    node: m.monitor.id,
+                    node_name: m.node.map(|node| node.name),
+                    created_at: m.monitor.created_at,
+                    confirmed_at: m.monitor.confirmed_at,
+                    confirmed_via: m.monitor.confirmed_via,
+                })
+                .collect();
+            let emails_sent = rate_limit_events::table
+                .filter(rate_limit_events::kind.eq("email"))
+                .filter(rate_limit_events::key.eq(email_hash))
+                .order_by(rate_limit_events::created_at)
+                .select(rate_limit_events::created_at)
+                .load(db)?;
+            let audit_log = audit_log::table
+                .filter(audit_log::email.eq(&*email))
+                .order_by(audit_log::id)
+                .load::<AuditLogQuery>(db)?
+                .into_iter()
+                .map(|e| AuditExport {
+                    at: e.created_at,
+                    node: e.node,
+                    action: e.action,
+                    via: e.via,
+                    client_ip_hash: e.client_ip_hash,
+                })
+                .collect();
+            let api_tokens = api_tokens::table
+                .filter(api_tokens::email.eq(&*email))
+                .order_by(api_tokens::id)
+                .select((
+                    api_tokens::label,
+                    api_tokens::created_at,
+                    api_tokens::last_used_at,
+                ))
+                .load::<(String, DateTime<Utc>, Option<DateTime<Utc>>)>(db)?
+                .into_iter()
+                .map(|(label, created_at, last_used_at)| ApiTokenExport {
+                    label,
+                    created_at,
+                    last_used_at,
+                })
+                .collect();
+            let webhooks = webhooks::table
+                .filter(webhooks::email.eq(&*email))
+                .order_by(webhooks::id)
+                .load::<WebhookQuery>(db)?
+                .into_iter()
+                .map(|hook| WebhookExport {
+                    url: hook.url,
+                    created_at: hook.created_at,
+                })
+                .collect();
+            let push_targets = push_targets::table
+                .filter(push_targets::email.eq(&*email))
+                .order_by(push_targets::id)
+                .load::<PushTargetQuery>(db)?
+                .into_iter()
+                .map(|target| PushTargetExport {
+                    kind: target.kind,
+                    url: target.url,
+                    created_at: target.created_at,
+                })
+                .collect();
+            let pending_push_targets = pending_push_targets::table
+                .filter(pending_push_targets::email.eq(&*email))
+                .order_by(pending_push_targets::valid_until)
+                .select((
+                    pending_push_targets::kind,
+                    pending_push_targets::url,
+                    pending_push_targets::valid_until,
+                ))
+                .load::<(String, String, DateTime<Utc>)>(db)?
+                .into_iter()
+                .map(|(kind, url, valid_until)| PendingPushTargetExport {
+                    kind,
+                    url,
+                    valid_until,
+                })
+                .collect();
+            let matrix_rooms = matrix_rooms::table
+                .filter(matrix_rooms::email.eq(&*email))
+                .order_by(matrix_rooms::id)
+                .load::<MatrixRoomQuery>(db)?
+                .into_iter()
+                .map(|room| MatrixRoomExport {
+                    target: room.target,
+                    room_id: room.room_id,
+                    created_at: room.created_at,
+                    pending: room.confirmation_code_hash.is_some(),
+                })
+                .collect();
+            let telegram_chats = telegram_chats::table
+                .filter(telegram_chats::email.eq(&*email))
+                .order_by(telegram_chats::id)
+                .load::<TelegramChatQuery>(db)?
+                .into_iter()
+                .map(|chat| TelegramChatExport {
+                    chat_id: chat.chat_id,
+                    created_at: chat.created_at,
+                })
+                .collect();
+            let telegram_links = telegram_links::table
+                .filter(telegram_links::email.eq(&*email))
+                .order_by(telegram_links::created_at)
+                .select((telegram_links::token_hash, telegram_links::created_at))
+                .load::<(String, DateTime<Utc>)>(db)?
+                .into_iter()
+                .map(|(token_hash, created_at)| TelegramLinkExport {
+                    token_hash,
+                    created_at,
+                })
+                .collect();
+            let sessions_reset_at = session_resets::table
+                .find(&*email)
+                .select(session_resets::reset_at)
+                .first(db)
+                .optional()?;
+            let feed_created_at = feed_secrets::table
+                .find(&*email)
+                .select(feed_secrets::created_at)
+                .first(db)
+                .optional()?;
+            Ok(DataExport {
+                email,
+                exported_at: Utc::now(),
+                monitors,
+                emails_sent,
+                audit_log,
+                api_tokens,
+                webhooks,
+                push_targets,
+                pending_push_targets,
+                matrix_rooms,
+                telegram_chats,
+                telegram_links,
+                sessions_reset_at,
+                feed_created_at,
+            })
+        })
+        .await
+    }
+}
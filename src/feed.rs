@@ -0,0 +1,81 @@
+//  ff-node-monitor -- Monitoring for Freifunk nodes
+//  Copyright (C) 2018  Ralf Jung <post AT ralfj DOT de>
+//
+//  This program is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU Affero General Public License as published by
+//  the Free Software Foundation, either version 3 of the License, or
+//  (at your option) any later version.
+//
+//  This program is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//  GNU Affero General Public License for more details.
+//
+//  You should have received a copy of the GNU Affero General Public License
+//  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! The per-address secret that feed URLs are tied to, so that they can be revoked
+
+use anyhow::Result;
+use diesel::prelude::*;
+
+use crate::action::SubscriberFeed;
+use crate::config::Secrets;
+use crate::db::DbConn;
+use crate::schema::*;
+use crate::util::{random_secret, secrets_equal};
+
+/// Get the feed secret of `email`, creating one if there is none yet
+pub fn secret(db: &mut PgConnection, email: &str) -> Result<String> {
+    diesel::insert_into(feed_secrets::table)
+        .values((
+            feed_secrets::email.eq(email),
+            feed_secrets::secret.eq(random_secret()?),
+        ))
+        .on_conflict_do_nothing()
+        .execute(db)?;
+    Ok(feed_secrets::table
+        .find(email)
+        .select(feed_secrets::secret)
+        .first(db)?)
+}
+
+/// Invalidate all feed URLs of `email`; a new secret is created when one is needed again
+pub fn reset(db: &mut PgConnection, email: &str) -> QueryResult<()> {
+    diesel::delete(feed_secrets::table.find(email)).execute(db)?;
+    Ok(())
+}
+
+/// Whether the (already verified) feed token still has the current secret of its address
+pub async fn check(db: &DbConn, secrets: &Secrets, feed: &SubscriberFeed) -> Result<bool> {
+    let email = feed.email.clone();
+    let current = db
+        .run(move |db| {
+            feed_secrets::table
+                .find(&*email)
+                .select(feed_secrets::secret)
+                .first::<String>(db)
+                .optional()
+        })
+        .await?;
+    Ok(current.is_some_and(|current| secrets_equal(secrets, &current, &feed.secret)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+
+    #[test]
+    #[ignore = "needs a PostgreSQL database in TEST_DATABASE_URL"]
+    fn secret_is_kept_until_reset() {
+        let mut db = db::test_connection();
+        let email = format!("feed-{}@example.org", random_secret().unwrap());
+        let first = secret(&mut db, &email).unwrap();
+        assert_eq!(secret(&mut db, &email).unwrap(), first);
+        reset(&mut db, &email).unwrap();
+        let second = secret(&mut db, &email).unwrap();
+        assert_ne!(second, first);
+        reset(&mut db, &email).unwrap();
+    }
+}
@@ -0,0 +1,120 @@
+//  ff-node-monitor -- Monitoring for Freifunk nodes
+//  Copyright (C) 2018  Ralf Jung <post AT ralfj DOT de>
+//
+//  This program is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU Affero General Public License as published by
+//  the Free Software Foundation, either version 3 of the License, or
+//  (at your option) any later version.
+//
+//  This program is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//  GNU Affero General Public License for more details.
+//
+//  You should have received a copy of the GNU Affero General Public License
+//  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use chrono::{DateTime, Duration, Utc};
+use diesel::prelude::*;
+use serde::Serialize;
+
+use crate::db::DbConn;
+use crate::email;
+use crate::schema::*;
+use crate::util::Ctx;
+
+/// The result of one readiness check.  Details about failures only go to the log, the endpoint is
+/// public.
+#[derive(Serialize)]
+pub struct Check {
+    pub ok: bool,
+}
+
+impl Check {
+    fn ok() -> Self {
+        Check { ok: true }
+    }
+
+    fn failed(message: impl std::fmt::Display) -> Self {
+        rocket::warn!("Readiness check failed: {}", message);
+        Check { ok: false }
+    }
+}
+
+#[derive(Serialize)]
+pub struct Readiness {
+    pub ready: bool,
+    pub database: Check,
+    pub cron: Check,
+    /// When the node list was last updated successfully
+    pub last_cron_success: Option<DateTime<Utc>>,
+    /// Only checked if `check_smtp` is set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub smtp: Option<Check>,
+}
+
+impl<'r> Ctx<'r> {
+    /// Check whether we can do our job: the database works, the node list is up-to-date, and
+    /// (if configured) we can send emails.  `db` is `None` if no connection could be obtained.
+    pub async fn check_readiness(&self, db: Option<&DbConn>) -> Readiness {
+        let config = self.config();
+
+        let (database, last_cron_success) = match db {
+            None => (Check::failed("no database connection available"), None),
+            Some(db) => {
+                let last_cron_success = db
+                    .run(|db| {
+                        cron_runs::table
+                            .filter(cron_runs::outcome.eq("ok"))
+                            .order_by(cron_runs::id.desc())
+                            .select(cron_runs::created_at)
+                            .first::<DateTime<Utc>>(db)
+                            .optional()
+                    })
+                    .await;
+                match last_cron_success {
+                    Ok(last_cron_success) => (Check::ok(), last_cron_success),
+                    Err(e) => (Check::failed(format!("database query failed: {}", e)), None),
+                }
+            }
+        };
+
+        let max_age = Duration::seconds(config.health.max_cron_age.into());
+        let cron = match last_cron_success {
+            _ if !database.ok => Check::failed("cannot check without database"),
+            None => Check::failed("the node list was never updated successfully"),
+            Some(at) if Utc::now() - at > max_age => Check::failed(format!(
+                "the node list was last updated successfully {} minutes ago",
+                (Utc::now() - at).num_minutes()
+            )),
+            Some(_) => Check::ok(),
+        };
+
+        let smtp = if config.health.check_smtp {
+            let reachable = match email::mailer(config) {
+                Ok(mailer) => mailer.test_connection().await.map_err(anyhow::Error::from),
+                Err(e) => Err(e),
+            };
+            Some(match reachable {
+                Ok(true) => Check::ok(),
+                Ok(false) => Check::failed("SMTP host did not accept the connection"),
+                Err(e) => Check::failed(format!("cannot connect to SMTP host: {}", e)),
+            })
+        } else {
+            None
+        };
+
+        // Not using `Option::is_none_or`, which needs a newer Rust than CI uses
+        let smtp_ok = match &smtp {
+            Some(smtp) => smtp.ok,
+            None => true,
+        };
+        Readiness {
+            ready: database.ok && cron.ok && smtp_ok,
+            database,
+            cron,
+            last_cron_success,
+            smtp,
+        }
+    }
+}
@@ -15,25 +15,95 @@
 //  along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 mod action;
+mod alertmanager;
+mod api;
+mod apitoken;
 mod config;
+mod confirm;
 mod cron;
 mod db;
 mod email;
+mod export;
+mod feed;
+mod health;
+mod matrix;
+mod metrics;
+#[cfg(test)]
+mod mockserver;
 mod models;
+mod notify;
+mod push;
+mod ratelimit;
 mod routes;
 mod schema;
+mod session;
+mod telegram;
 mod util;
+mod webhook;
+
+use rocket::{Build, Rocket};
 
 #[rocket::launch]
 fn rocket() -> _ {
     // Launch the rocket (also initializes `log` facade)
-    rocket::build()
+    app(rocket::build()
         .attach(db::DbConn::fairing())
-        .attach(db::migration())
+        .attach(db::migration()))
+}
+
+/// Everything but the database
+fn app(rocket: Rocket<Build>) -> Rocket<Build> {
+    rocket
         .attach(config::fairing("ff-node-monitor"))
+        .manage(metrics::Metrics::default())
         .attach(rocket_dyn_templates::Template::custom(|engines| {
             engines.handlebars.set_strict_mode(true);
+            engines
+                .handlebars
+                .register_helper("date", Box::new(util::date));
+            engines
+                .handlebars
+                .register_helper("datetime", Box::new(util::datetime));
+            engines
+                .handlebars
+                .register_helper("percent", Box::new(util::percent));
         }))
         .mount("/static", rocket::fs::FileServer::from("static"))
         .mount("/", routes::routes())
+        .mount("/api/v1", api::routes())
+        .register("/api/v1", api::catchers())
+}
+
+/// The basic configuration for tests
+#[cfg(test)]
+const TEST_CONFIG: &str = r#"
+[ff-node-monitor.ui]
+instance_name = "Test-Knotenüberwachung"
+instance_article_dative = "der"
+email_from = "monitor@example.org"
+
+[ff-node-monitor.urls]
+root = "http://localhost/"
+nodes = "http://localhost/nodes.json"
+sources = "https://github.com/freifunk-saar/ff-node-monitor"
+
+[ff-node-monitor.secrets]
+action_signing_keys = { 1 = "00112233" }
+action_signing_key_id = "1"
+personal_data_key = "44556677"
+"#;
+
+/// The whole application on top of the test database, configured by [`TEST_CONFIG`] extended with
+/// `config` (in TOML)
+#[cfg(test)]
+async fn test_app(config: &str) -> rocket::local::asynchronous::Client {
+    use rocket::figment::providers::{Format, Toml};
+
+    let figment = db::test_figment()
+        .merge(Toml::string(TEST_CONFIG))
+        .merge(Toml::string(config));
+    let rocket = app(rocket::custom(figment).attach(db::DbConn::fairing()));
+    rocket::local::asynchronous::Client::untracked(rocket)
+        .await
+        .expect("could not start test rocket")
 }
@@ -0,0 +1,429 @@
+//  ff-node-monitor -- Monitoring for Freifunk nodes
+//  Copyright (C) 2018  Ralf Jung <post AT ralfj DOT de>
+//
+//  This program is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU Affero General Public License as published by
+//  the Free Software Foundation, either version 3 of the License, or
+//  (at your option) any later version.
+//
+//  This program is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//  GNU Affero General Public License for more details.
+//
+//  You should have received a copy of the GNU Affero General Public License
+//  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use chrono::Utc;
+use diesel::prelude::*;
+use serde::Deserialize;
+use serde_json::json;
+use url::Url;
+
+use crate::config::{Config, Secrets};
+use crate::db::DbConn;
+use crate::email::EmailAddress;
+use crate::models::*;
+use crate::notify::{Event, Notifier};
+use crate::schema::*;
+use crate::util::{hash_personal_data, random_secret, secrets_equal, Ctx};
+
+/// How often we try to send a message, and how long we wait before each retry
+const RETRY_DELAYS: [u64; 3] = [0, 10, 60];
+/// How long the code sent to a new room can be entered, in seconds
+const CONFIRMATION_VALIDITY: i64 = 24 * 60 * 60;
+/// Length of that code
+const CODE_LENGTH: usize = 12;
+
+/// Whether this looks like a Matrix user ID (`@user:server`), room ID (`!room:server`) or room
+/// alias (`#room:server`)
+pub fn is_valid_target(target: &str) -> bool {
+    let rest = match target.strip_prefix(['@', '!', '#']) {
+        Some(rest) => rest,
+        None => return false,
+    };
+    match rest.split_once(':') {
+        Some((local, server)) => !local.is_empty() && !server.is_empty(),
+        None => false,
+    }
+}
+
+#[derive(Deserialize)]
+struct RoomResponse {
+    room_id: String,
+}
+
+/// Talks to the homeserver of the bot account via the client-server API
+#[derive(Clone)]
+pub struct Client {
+    http: reqwest::Client,
+    homeserver: Url,
+    access_token: String,
+}
+
+impl Client {
+    /// The client for the configured bot account, if Matrix is enabled
+    pub fn new(config: &Config) -> Result<Option<Client>> {
+        let (matrix, access_token) = match (&config.matrix, &config.secrets.matrix_access_token) {
+            (Some(matrix), Some(access_token)) => (matrix, access_token),
+            _ => return Ok(None),
+        };
+        Ok(Some(Client {
+            http: reqwest::Client::builder()
+                .timeout(Duration::from_secs(10))
+                .build()?,
+            homeserver: matrix.homeserver.clone(),
+            access_token: access_token.clone(),
+        }))
+    }
+
+    /// The URL of a client-server API endpoint; `path` is percent-encoded as needed
+    fn endpoint(&self, path: &[&str]) -> Url {
+        let mut url = self.homeserver.clone();
+        url.path_segments_mut()
+            .expect("the homeserver URL cannot be a base")
+            .pop_if_empty()
+            .extend(["_matrix", "client", "v3"])
+            .extend(path);
+        url
+    }
+
+    async fn request(
+        &self,
+        method: reqwest::Method,
+        path: &[&str],
+        body: &serde_json::Value,
+    ) -> Result<reqwest::Response> {
+        Ok(self
+            .http
+            .request(method, self.endpoint(path))
+            .bearer_auth(&self.access_token)
+            .json(body)
+            .send()
+            .await?
+            .error_for_status()?)
+    }
+
+    /// Find the room to send messages for `target` to: join the given room, or open a direct
+    /// chat with the given user.
+    pub async fn resolve(&self, target: &str) -> Result<String> {
+        if !is_valid_target(target) {
+            bail!("invalid Matrix target: {}", target);
+        }
+        let response = if target.starts_with('@') {
+            self.request(
+                reqwest::Method::POST,
+                &["createRoom"],
+                &json!({
+                    "invite": [target],
+                    "is_direct": true,
+                    "preset": "trusted_private_chat",
+                }),
+            )
+            .await
+        } else {
+            self.request(reqwest::Method::POST, &["join", target], &json!({}))
+                .await
+        };
+        let room: RoomResponse = response
+            .with_context(|| format!("failed to open Matrix room for {}", target))?
+            .json()
+            .await?;
+        Ok(room.room_id)
+    }
+
+    /// Send a text message to the room in the background, retrying a few times on failure
+    pub fn send(&self, room_id: &str, text: String) {
+        let client = self.clone();
+        let room_id = room_id.to_owned();
+        rocket::tokio::spawn(async move {
+            // Re-using the transaction ID makes sure retries do not show up twice
+            let txn_id = match random_secret() {
+                Ok(txn_id) => txn_id,
+                Err(e) => {
+                    return rocket::error!("Sending to Matrix room {} failed: {}", room_id, e)
+                }
+            };
+            let body = json!({
+                "msgtype": "m.notice",
+                "body": text.trim_end(),
+            });
+            for delay in RETRY_DELAYS {
+                rocket::tokio::time::sleep(Duration::from_secs(delay)).await;
+                let path = ["rooms", &room_id, "send", "m.room.message", &txn_id];
+                match client.request(reqwest::Method::PUT, &path, &body).await {
+                    Ok(_) => return,
+                    Err(e) => rocket::warn!("Sending to Matrix room {} failed: {}", room_id, e),
+                }
+            }
+            rocket::error!("Giving up sending to Matrix room {}", room_id);
+        });
+    }
+}
+
+/// Add a Matrix room (found via [`Client::resolve`]) to notify for `email`.  Returns a code that
+/// is to be sent to the room; notifications only start once the user entered it (see [`confirm`]),
+/// so that nobody can make us send messages to people who do not want them.
+pub async fn create(
+    db: &DbConn,
+    secrets: &Secrets,
+    email: &EmailAddress,
+    target: &str,
+    room_id: &str,
+) -> Result<String> {
+    let code = random_secret()?[..CODE_LENGTH].to_owned();
+    let code_hash = hash_personal_data(secrets, &code);
+    let email = email.clone();
+    let target = target.to_owned();
+    let room_id = room_id.to_owned();
+    db.run(move |db| {
+        // Clean up expired codes, and replace an earlier code for the same room
+        let expired = Utc::now() - chrono::Duration::seconds(CONFIRMATION_VALIDITY);
+        let pending =
+            matrix_rooms::table.filter(matrix_rooms::confirmation_code_hash.is_not_null());
+        diesel::delete(pending.filter(matrix_rooms::created_at.lt(expired))).execute(db)?;
+        diesel::delete(
+            pending
+                .filter(matrix_rooms::email.eq(&*email))
+                .filter(matrix_rooms::room_id.eq(&room_id)),
+        )
+        .execute(db)?;
+        diesel::insert_into(matrix_rooms::table)
+            .values(&MatrixRoom {
+                email: &email,
+                target: &target,
+                room_id: &room_id,
+                confirmation_code_hash: Some(&code_hash),
+            })
+            .execute(db)
+    })
+    .await?;
+    Ok(code)
+}
+
+/// Start notifying the Matrix room with the given ID, if it belongs to `email` and `code` is the
+/// code we sent to it.  Returns whether that was the case.
+pub async fn confirm(
+    db: &DbConn,
+    secrets: &Secrets,
+    email: &EmailAddress,
+    id: i64,
+    code: &str,
+) -> Result<bool> {
+    let code_hash = hash_personal_data(secrets, code.trim());
+    let email = email.clone();
+    let expected = db
+        .run(move |db| {
+            let expired = Utc::now() - chrono::Duration::seconds(CONFIRMATION_VALIDITY);
+            matrix_rooms::table
+                .filter(matrix_rooms::id.eq(id))
+                .filter(matrix_rooms::email.eq(&*email))
+                .filter(matrix_rooms::created_at.ge(expired))
+                .select(matrix_rooms::confirmation_code_hash)
+                .first::<Option<String>>(db)
+                .optional()
+        })
+        .await?;
+    let expected = match expected {
+        Some(Some(expected)) => expected,
+        // Unknown, expired or already confirmed
+        _ => return Ok(false),
+    };
+    if !secrets_equal(secrets, &expected, &code_hash) {
+        return Ok(false);
+    }
+    db.run(move |db| {
+        diesel::update(matrix_rooms::table.find(id))
+            .set((
+                matrix_rooms::confirmation_code_hash.eq(None::<String>),
+                // Show since when notifications are sent
+                matrix_rooms::created_at.eq(Utc::now()),
+            ))
+            .execute(db)
+    })
+    .await?;
+    Ok(true)
+}
+
+/// The Matrix rooms of `email` that are notified, newest first
+pub async fn list(db: &DbConn, email: &EmailAddress) -> Result<Vec<MatrixRoomQuery>> {
+    let email = email.clone();
+    Ok(db
+        .run(move |db| {
+            matrix_rooms::table
+                .filter(matrix_rooms::email.eq(&*email))
+                .filter(matrix_rooms::confirmation_code_hash.is_null())
+                .order_by(matrix_rooms::id.desc())
+                .load::<MatrixRoomQuery>(db)
+        })
+        .await?)
+}
+
+/// The Matrix rooms of `email` whose code can still be entered, newest first
+pub async fn list_pending(db: &DbConn, email: &EmailAddress) -> Result<Vec<MatrixRoomQuery>> {
+    let email = email.clone();
+    Ok(db
+        .run(move |db| {
+            let expired = Utc::now() - chrono::Duration::seconds(CONFIRMATION_VALIDITY);
+            matrix_rooms::table
+                .filter(matrix_rooms::email.eq(&*email))
+                .filter(matrix_rooms::confirmation_code_hash.is_not_null())
+                .filter(matrix_rooms::created_at.ge(expired))
+                .order_by(matrix_rooms::id.desc())
+                .load::<MatrixRoomQuery>(db)
+        })
+        .await?)
+}
+
+/// Remove the Matrix room with the given ID, if it belongs to `email`.  Returns whether it existed.
+pub async fn remove(db: &DbConn, email: &EmailAddress, id: i64) -> Result<bool> {
+    let email = email.clone();
+    let num_deleted = db
+        .run(move |db| {
+            diesel::delete(
+                matrix_rooms::table
+                    .filter(matrix_rooms::id.eq(id))
+                    .filter(matrix_rooms::email.eq(&*email)),
+            )
+            .execute(db)
+        })
+        .await?;
+    Ok(num_deleted > 0)
+}
+
+/// The Matrix rooms of the given subscribers
+pub async fn for_subscribers(db: &DbConn, subscribers: &[String]) -> Result<Vec<MatrixRoomQuery>> {
+    let subscribers = subscribers.to_vec();
+    Ok(db
+        .run(move |db| {
+            matrix_rooms::table
+                .filter(matrix_rooms::email.eq_any(subscribers))
+                .filter(matrix_rooms::confirmation_code_hash.is_null())
+                .load::<MatrixRoomQuery>(db)
+        })
+        .await?)
+}
+
+/// Notifications via Matrix
+#[rocket::async_trait]
+impl Notifier for Client {
+    async fn notify(
+        &self,
+        ctx: &Ctx<'_>,
+        db: &DbConn,
+        event: &Event<'_>,
+        subscribers: &[String],
+    ) -> Result<()> {
+        let rooms = for_subscribers(db, subscribers).await?;
+        if rooms.is_empty() {
+            return Ok(());
+        }
+        let text = event.text(ctx)?;
+        // Messages are sent in the background
+        for room in rooms {
+            self.send(&room.room_id, text.clone());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mockserver::MockServer;
+    use crate::session::Session;
+    use rocket::http::{ContentType, Status};
+
+    fn form(fields: &[(&str, &str)]) -> String {
+        url::form_urlencoded::Serializer::new(String::new())
+            .extend_pairs(fields)
+            .finish()
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs a PostgreSQL database in TEST_DATABASE_URL"]
+    async fn add_and_confirm_room() {
+        let homeserver = MockServer::start(|_| json!({ "room_id": "!room:example.org" })).await;
+        let client = crate::test_app(&format!(
+            "[ff-node-monitor.secrets]\nmatrix_access_token = \"bot-token\"\n\
+             [ff-node-monitor.matrix]\nhomeserver = \"{}\"",
+            homeserver.url
+        ))
+        .await;
+        let db = DbConn::get_one(client.rocket()).await.unwrap();
+        let email = format!("matrix-{}@example.org", random_secret().unwrap());
+        let email = EmailAddress::new(email).unwrap();
+
+        let response = client
+            .post("/add_matrix_room")
+            .header(ContentType::Form)
+            .private_cookie(Session::test_cookie(&email))
+            .body(form(&[("email", &email), ("target", "@alice:example.org")]))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::SeeOther);
+
+        // A direct chat is opened, and the code is sent there in the background
+        let requests = homeserver.requests(2).await;
+        for request in &requests {
+            assert_eq!(request.headers["authorization"], "Bearer bot-token");
+        }
+        let (create, send) = (&requests[0], &requests[1]);
+        assert_eq!(create.method, "POST");
+        assert_eq!(create.path, "/_matrix/client/v3/createRoom");
+        assert_eq!(create.body["invite"], json!(["@alice:example.org"]));
+        assert_eq!(create.body["is_direct"], json!(true));
+        assert_eq!(send.method, "PUT");
+        assert!(send
+            .path
+            .starts_with("/_matrix/client/v3/rooms/!room:example.org/send/m.room.message/"));
+        assert_eq!(send.body["msgtype"], "m.notice");
+        let message = send.body["body"].as_str().unwrap();
+        assert!(message.contains(&*email));
+        let code = message
+            .split("Code ein: ")
+            .nth(1)
+            .and_then(|rest| rest.split_whitespace().next())
+            .unwrap();
+
+        // Until the code is entered, the room is not notified
+        let pending = list_pending(&db, &email).await.unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].room_id, "!room:example.org");
+        let subscribers = [email.to_string()];
+        assert!(for_subscribers(&db, &subscribers).await.unwrap().is_empty());
+
+        let id = pending[0].id.to_string();
+        for (code, status) in [("wrong", Status::Ok), (code, Status::SeeOther)] {
+            let response = client
+                .post("/confirm_matrix_room")
+                .header(ContentType::Form)
+                .private_cookie(Session::test_cookie(&email))
+                .body(form(&[("email", &email), ("id", &id), ("code", code)]))
+                .dispatch()
+                .await;
+            assert_eq!(response.status(), status);
+        }
+        assert!(list_pending(&db, &email).await.unwrap().is_empty());
+        let rooms = for_subscribers(&db, &subscribers).await.unwrap();
+        assert_eq!(rooms.len(), 1);
+        assert_eq!(rooms[0].target, "@alice:example.org");
+        remove(&db, &email, rooms[0].id).await.unwrap();
+
+        // Rooms are joined via their alias
+        let matrix = Client::new(client.rocket().state::<Config>().unwrap())
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            matrix.resolve("#ffnm:example.org").await.unwrap(),
+            "!room:example.org"
+        );
+        let join = &homeserver.requests(3).await[2];
+        assert_eq!(join.method, "POST");
+        assert_eq!(join.path, "/_matrix/client/v3/join/%23ffnm:example.org");
+        assert_eq!(join.headers["authorization"], "Bearer bot-token");
+    }
+}
@@ -0,0 +1,338 @@
+//  ff-node-monitor -- Monitoring for Freifunk nodes
+//  Copyright (C) 2018  Ralf Jung <post AT ralfj DOT de>
+//
+//  This program is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU Affero General Public License as published by
+//  the Free Software Foundation, either version 3 of the License, or
+//  (at your option) any later version.
+//
+//  This program is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//  GNU Affero General Public License for more details.
+//
+//  You should have received a copy of the GNU Affero General Public License
+//  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::Result;
+use diesel::dsl::{count_distinct, count_star};
+use diesel::prelude::*;
+
+use rocket::http::Status;
+use rocket::request::{self, FromRequest, Outcome};
+use rocket::Request;
+
+use crate::action::Operation;
+use crate::config::Config;
+use crate::cron::{self, UpdateResult};
+use crate::db::DbConn;
+use crate::schema::*;
+use crate::util::{secrets_equal, Ctx};
+
+/// Counters for things happening in this process.  Everything that is in the database is instead
+/// determined when the metrics are requested.
+#[derive(Default)]
+pub struct Metrics {
+    /// Number of cron runs by outcome (see `cron::outcome`)
+    cron_runs: Mutex<BTreeMap<&'static str, u64>>,
+    /// How long cron runs took
+    cron_duration: Mutex<Histogram>,
+    /// Number of times fetching the node list failed
+    feed_errors: AtomicU64,
+    /// Number of times a notification channel failed during a cron run
+    notification_errors: AtomicU64,
+    /// Number of emails sent (`true`) and failed (`false`) by template
+    emails: Mutex<BTreeMap<(&'static str, bool), u64>>,
+    /// Number of actions executed, by operation
+    actions_add: AtomicU64,
+    actions_remove: AtomicU64,
+    /// Number of links in emails that were used to confirm something, by what they confirmed
+    confirmations: Mutex<BTreeMap<&'static str, u64>>,
+}
+
+/// Upper bounds (in seconds) of the buckets of the cron duration histogram
+const CRON_DURATION_BUCKETS: [f64; 8] = [0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+/// A Prometheus histogram with the `CRON_DURATION_BUCKETS`
+#[derive(Default)]
+struct Histogram {
+    /// Number of observations in each bucket (not cumulative)
+    buckets: [u64; CRON_DURATION_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        if let Some(bucket) = CRON_DURATION_BUCKETS.iter().position(|le| value <= *le) {
+            self.buckets[bucket] += 1;
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+}
+
+impl Metrics {
+    pub fn cron_run(&self, result: &Result<UpdateResult>, duration: Duration) {
+        *self
+            .cron_runs
+            .lock()
+            .unwrap()
+            .entry(cron::outcome(result))
+            .or_default() += 1;
+        self.cron_duration
+            .lock()
+            .unwrap()
+            .observe(duration.as_secs_f64());
+    }
+
+    pub fn feed_error(&self) {
+        self.feed_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn notification_errors(&self, count: usize) {
+        self.notification_errors
+            .fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    pub fn email(&self, template: &'static str, success: bool) {
+        *self
+            .emails
+            .lock()
+            .unwrap()
+            .entry((template, success))
+            .or_default() += 1;
+    }
+
+    pub fn action(&self, op: Operation) {
+        let counter = match op {
+            Operation::Add => &self.actions_add,
+            Operation::Remove => &self.actions_remove,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a link from an email that was used to confirm `kind`
+    pub fn confirmation(&self, kind: &'static str) {
+        *self.confirmations.lock().unwrap().entry(kind).or_default() += 1;
+    }
+}
+
+/// A request guard checking the `metrics_token` from the config, if any
+pub struct MetricsAccess;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for MetricsAccess {
+    type Error = ();
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let config = request.rocket().state::<Config>().unwrap();
+        let metrics_token = match config.secrets.metrics_token.as_deref() {
+            Some(metrics_token) => metrics_token,
+            None => return Outcome::Success(MetricsAccess),
+        };
+        let token = request
+            .headers()
+            .get_one("Authorization")
+            .and_then(|header| header.strip_prefix("Bearer "));
+        match token {
+            Some(token) if secrets_equal(&config.secrets, token, metrics_token) => {
+                Outcome::Success(MetricsAccess)
+            }
+            _ => Outcome::Error((Status::Unauthorized, ())),
+        }
+    }
+}
+
+/// Accumulates metrics in the Prometheus text format
+#[derive(Default)]
+struct Exposition(String);
+
+impl Exposition {
+    fn header(&mut self, name: &str, kind: &str, help: &str) {
+        writeln!(self.0, "# HELP ff_node_monitor_{} {}", name, help).unwrap();
+        writeln!(self.0, "# TYPE ff_node_monitor_{} {}", name, kind).unwrap();
+    }
+
+    fn value(&mut self, name: &str, labels: &[(&str, &str)], value: impl std::fmt::Display) {
+        let labels: Vec<String> = labels
+            .iter()
+            .map(|(key, val)| format!("{}=\"{}\"", key, val))
+            .collect();
+        if labels.is_empty() {
+            writeln!(self.0, "ff_node_monitor_{} {}", name, value).unwrap();
+        } else {
+            writeln!(
+                self.0,
+                "ff_node_monitor_{}{{{}}} {}",
+                name,
+                labels.join(","),
+                value
+            )
+            .unwrap();
+        }
+    }
+}
+
+impl<'r> Ctx<'r> {
+    pub fn metrics(&self) -> &Metrics {
+        self.state::<Metrics>().unwrap()
+    }
+
+    /// Render all metrics in the Prometheus text format
+    pub async fn render_metrics(&self, db: &DbConn) -> Result<String> {
+        let (online, offline, monitors, subscribers) = db
+            .run_transaction(|db| {
+                let online: i64 = nodes::table
+                    .filter(nodes::online.eq(true))
+                    .select(count_star())
+                    .get_result(db)?;
+                let offline: i64 = nodes::table
+                    .filter(nodes::online.eq(false))
+                    .select(count_star())
+                    .get_result(db)?;
+                let monitors: i64 = monitors::table.select(count_star()).get_result(db)?;
+                let subscribers: i64 = monitors::table
+                    .select(count_distinct(monitors::email))
+                    .get_result(db)?;
+                Ok((online, offline, monitors, subscribers))
+            })
+            .await?;
+        let metrics = self.metrics();
+        let mut out = Exposition::default();
+
+        out.header("nodes", "gauge", "Number of known nodes by state.");
+        out.value("nodes", &[("state", "online")], online);
+        out.value("nodes", &[("state", "offline")], offline);
+        out.header("known_nodes", "gauge", "Number of known nodes.");
+        out.value("known_nodes", &[], online + offline);
+        out.header(
+            "monitors",
+            "gauge",
+            "Number of monitored (node, email) pairs.",
+        );
+        out.value("monitors", &[], monitors);
+        out.header(
+            "subscribers",
+            "gauge",
+            "Number of distinct email addresses monitoring at least one node.",
+        );
+        out.value("subscribers", &[], subscribers);
+
+        out.header(
+            "cron_runs_total",
+            "counter",
+            "Number of cron runs by result.",
+        );
+        for (result, count) in metrics.cron_runs.lock().unwrap().iter() {
+            out.value("cron_runs_total", &[("result", result)], count);
+        }
+        out.header(
+            "cron_duration_seconds",
+            "histogram",
+            "How long cron runs took.",
+        );
+        {
+            let histogram = metrics.cron_duration.lock().unwrap();
+            let mut cumulative = 0;
+            for (le, count) in CRON_DURATION_BUCKETS.iter().zip(histogram.buckets.iter()) {
+                cumulative += count;
+                out.value(
+                    "cron_duration_seconds_bucket",
+                    &[("le", &le.to_string())],
+                    cumulative,
+                );
+            }
+            out.value(
+                "cron_duration_seconds_bucket",
+                &[("le", "+Inf")],
+                histogram.count,
+            );
+            out.value("cron_duration_seconds_sum", &[], histogram.sum);
+            out.value("cron_duration_seconds_count", &[], histogram.count);
+        }
+        out.header(
+            "feed_errors_total",
+            "counter",
+            "Number of times fetching the node list failed.",
+        );
+        out.value(
+            "feed_errors_total",
+            &[],
+            metrics.feed_errors.load(Ordering::Relaxed),
+        );
+
+        out.header(
+            "notification_errors_total",
+            "counter",
+            "Number of times a notification channel failed during a cron run.",
+        );
+        out.value(
+            "notification_errors_total",
+            &[],
+            metrics.notification_errors.load(Ordering::Relaxed),
+        );
+
+        out.header(
+            "emails_total",
+            "counter",
+            "Number of emails by template and result.",
+        );
+        for ((template, success), count) in metrics.emails.lock().unwrap().iter() {
+            let result = if *success { "sent" } else { "failed" };
+            out.value(
+                "emails_total",
+                &[("template", template), ("result", result)],
+                count,
+            );
+        }
+
+        out.header(
+            "actions_total",
+            "counter",
+            "Number of confirmed actions executed by operation.",
+        );
+        out.value(
+            "actions_total",
+            &[("op", Operation::Add.as_str())],
+            metrics.actions_add.load(Ordering::Relaxed),
+        );
+        out.value(
+            "actions_total",
+            &[("op", Operation::Remove.as_str())],
+            metrics.actions_remove.load(Ordering::Relaxed),
+        );
+        out.header(
+            "confirmations_total",
+            "counter",
+            "Number of links in emails used to confirm something, by what they confirmed.",
+        );
+        for (kind, count) in metrics.confirmations.lock().unwrap().iter() {
+            out.value("confirmations_total", &[("kind", kind)], count);
+        }
+
+        Ok(out.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_buckets() {
+        let mut histogram = Histogram::default();
+        histogram.observe(0.05);
+        histogram.observe(0.1);
+        histogram.observe(3.0);
+        histogram.observe(100.0);
+        assert_eq!(histogram.buckets, [2, 0, 0, 0, 0, 1, 0, 0]);
+        assert_eq!(histogram.count, 4);
+        assert_eq!(histogram.sum, 103.15);
+    }
+}
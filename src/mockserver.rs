@@ -0,0 +1,136 @@
+//  ff-node-monitor -- Monitoring for Freifunk nodes
+//  Copyright (C) 2018  Ralf Jung <post AT ralfj DOT de>
+//
+//  This program is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU Affero General Public License as published by
+//  the Free Software Foundation, either version 3 of the License, or
+//  (at your option) any later version.
+//
+//  This program is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//  GNU Affero General Public License for more details.
+//
+//  You should have received a copy of the GNU Affero General Public License
+//  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! A local HTTP server standing in for the APIs of external services in tests
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rocket::tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use rocket::tokio::net::{TcpListener, TcpStream};
+use url::Url;
+
+/// A request received by the [`MockServer`]
+#[derive(Clone, Debug)]
+pub struct MockRequest {
+    pub method: String,
+    /// Path and query, as sent (i.e., percent-encoded)
+    pub path: String,
+    /// Header names are lowercase
+    pub headers: HashMap<String, String>,
+    /// `null` if the body was empty
+    pub body: serde_json::Value,
+}
+
+type Responder = dyn Fn(&MockRequest) -> serde_json::Value + Send + Sync;
+
+/// Records all requests and answers each with `200 OK` and the JSON that `respond` returns for it
+pub struct MockServer {
+    pub url: Url,
+    requests: Arc<Mutex<Vec<MockRequest>>>,
+}
+
+impl MockServer {
+    pub async fn start(
+        respond: impl Fn(&MockRequest) -> serde_json::Value + Send + Sync + 'static,
+    ) -> MockServer {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let respond: Arc<Responder> = Arc::new(respond);
+        let recorded = requests.clone();
+        rocket::tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let (recorded, respond) = (recorded.clone(), respond.clone());
+                rocket::tokio::spawn(async move {
+                    if let Err(e) = handle(stream, &recorded, &*respond).await {
+                        eprintln!("mock server: {}", e);
+                    }
+                });
+            }
+        });
+        MockServer {
+            url: url.parse().unwrap(),
+            requests,
+        }
+    }
+
+    /// The requests received so far.  Waits until there are at least `count` of them, as some
+    /// requests are sent in the background.
+    pub async fn requests(&self, count: usize) -> Vec<MockRequest> {
+        for _ in 0..100 {
+            let requests = self.requests.lock().unwrap().clone();
+            if requests.len() >= count {
+                return requests;
+            }
+            rocket::tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        panic!("mock server did not receive {} requests", count);
+    }
+}
+
+/// Serve a single request on the connection, then close it
+async fn handle(
+    stream: TcpStream,
+    recorded: &Mutex<Vec<MockRequest>>,
+    respond: &Responder,
+) -> std::io::Result<()> {
+    let mut stream = BufReader::new(stream);
+    let mut line = String::new();
+    stream.read_line(&mut line).await?;
+    let mut request_line = line.split_whitespace();
+    let method = request_line.next().unwrap_or_default().to_owned();
+    let path = request_line.next().unwrap_or_default().to_owned();
+
+    let mut headers = HashMap::new();
+    loop {
+        line.clear();
+        stream.read_line(&mut line).await?;
+        match line.trim_end().split_once(':') {
+            Some((name, value)) => {
+                headers.insert(name.to_lowercase(), value.trim().to_owned());
+            }
+            None => break,
+        }
+    }
+    let length = headers
+        .get("content-length")
+        .map_or(0, |length| length.parse().unwrap());
+    let mut body = vec![0; length];
+    stream.read_exact(&mut body).await?;
+    let body = if body.is_empty() {
+        serde_json::Value::Null
+    } else {
+        serde_json::from_slice(&body)?
+    };
+
+    let request = MockRequest {
+        method,
+        path,
+        headers,
+        body,
+    };
+    let response = respond(&request).to_string();
+    recorded.lock().unwrap().push(request);
+    let response = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        response.len(),
+        response
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
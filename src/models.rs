@@ -14,6 +14,7 @@
 //  You should have received a copy of the GNU Affero General Public License
 //  along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+use chrono::{DateTime, Utc};
 use diesel::prelude::*;
 use serde::Serialize;
 
@@ -23,6 +24,12 @@ use crate::schema::*;
 pub struct MonitorQuery {
     pub id: String,
     pub email: String,
+    /// When monitoring was requested (NULL for old monitors)
+    pub created_at: Option<DateTime<Utc>>,
+    /// When monitoring was confirmed (NULL for old monitors)
+    pub confirmed_at: Option<DateTime<Utc>>,
+    /// How monitoring was confirmed (NULL for old monitors)
+    pub confirmed_via: Option<String>,
 }
 
 #[derive(Insertable, Identifiable)]
@@ -30,6 +37,9 @@ pub struct MonitorQuery {
 pub struct Monitor<'a> {
     pub id: &'a str,
     pub email: &'a str,
+    pub created_at: Option<DateTime<Utc>>,
+    pub confirmed_at: Option<DateTime<Utc>>,
+    pub confirmed_via: Option<&'a str>,
 }
 
 #[derive(Queryable, Serialize)]
@@ -37,6 +47,18 @@ pub struct NodeQuery {
     pub id: String,
     pub name: String,
     pub online: bool,
+    /// The remaining fields are copied from the node list (NULL for nodes not seen since they
+    /// were added)
+    pub first_seen: Option<DateTime<Utc>>,
+    pub last_seen: Option<DateTime<Utc>>,
+    pub clients: Option<i32>,
+    pub loadavg: Option<f64>,
+    /// Fraction of memory in use
+    pub memory_usage: Option<f64>,
+    /// Fraction of the root file system in use
+    pub rootfs_usage: Option<f64>,
+    /// The mesh domain (or site, if there are no domains) the node belongs to
+    pub domain: Option<String>,
 }
 
 #[derive(Queryable, Serialize)]
@@ -53,4 +75,194 @@ pub struct Node<'a> {
     pub id: &'a str,
     pub name: &'a str,
     pub online: bool,
+    pub first_seen: Option<DateTime<Utc>>,
+    pub last_seen: Option<DateTime<Utc>>,
+    pub clients: Option<i32>,
+    pub loadavg: Option<f64>,
+    pub memory_usage: Option<f64>,
+    pub rootfs_usage: Option<f64>,
+    pub domain: Option<&'a str>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = rate_limit_events)]
+pub struct RateLimitEvent<'a> {
+    pub kind: &'a str,
+    pub key: &'a str,
+}
+
+#[derive(Queryable, Serialize)]
+pub struct AuditLogQuery {
+    pub id: i64,
+    pub created_at: DateTime<Utc>,
+    pub email: String,
+    pub node: String,
+    /// "add" or "remove"
+    pub action: String,
+    /// How the action was confirmed, see `Confirmation::via`
+    pub via: String,
+    pub client_ip_hash: Option<String>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = audit_log)]
+pub struct AuditLogEntry<'a> {
+    pub created_at: DateTime<Utc>,
+    pub email: &'a str,
+    pub node: &'a str,
+    pub action: &'a str,
+    pub via: &'a str,
+    pub client_ip_hash: Option<&'a str>,
+}
+
+#[derive(Queryable, Serialize)]
+pub struct ApiTokenQuery {
+    pub id: i64,
+    pub email: String,
+    pub label: String,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = api_tokens)]
+pub struct ApiToken<'a> {
+    pub email: &'a str,
+    pub token_hash: &'a str,
+    pub label: &'a str,
+    pub request_id: Option<&'a str>,
+}
+
+/// A node went online or offline
+#[derive(Queryable, Serialize)]
+pub struct NodeEventQuery {
+    pub id: i64,
+    pub node: String,
+    pub online: bool,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = node_events)]
+pub struct NodeEvent<'a> {
+    pub node: &'a str,
+    pub online: bool,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Queryable, Serialize)]
+pub struct CronRunQuery {
+    pub id: i64,
+    pub created_at: DateTime<Utc>,
+    /// "ok", "not_enough_online" or "error"
+    pub outcome: String,
+    pub online_nodes: Option<i32>,
+    pub message: Option<String>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = cron_runs)]
+pub struct CronRun<'a> {
+    pub outcome: &'a str,
+    pub online_nodes: Option<i32>,
+    pub message: Option<&'a str>,
+}
+
+#[derive(Queryable, Serialize)]
+pub struct WebhookQuery {
+    pub id: i64,
+    pub email: String,
+    pub url: String,
+    /// Key for signing the payloads
+    #[serde(skip)]
+    pub secret: String,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = webhooks)]
+pub struct Webhook<'a> {
+    pub email: &'a str,
+    pub url: &'a str,
+    pub secret: &'a str,
+}
+
+#[derive(Queryable, Serialize)]
+pub struct MatrixRoomQuery {
+    pub id: i64,
+    pub email: String,
+    /// What the user entered: a user ID, room ID or room alias
+    pub target: String,
+    /// The room we send the messages to
+    pub room_id: String,
+    pub created_at: DateTime<Utc>,
+    /// Set until the code we sent to the room was entered
+    #[serde(skip)]
+    pub confirmation_code_hash: Option<String>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = matrix_rooms)]
+pub struct MatrixRoom<'a> {
+    pub email: &'a str,
+    pub target: &'a str,
+    pub room_id: &'a str,
+    pub confirmation_code_hash: Option<&'a str>,
+}
+
+#[derive(Queryable, Serialize)]
+pub struct TelegramChatQuery {
+    pub id: i64,
+    pub email: String,
+    pub chat_id: i64,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = telegram_chats)]
+pub struct TelegramChat<'a> {
+    pub email: &'a str,
+    pub chat_id: i64,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = telegram_links)]
+pub struct TelegramLink<'a> {
+    pub token_hash: &'a str,
+    pub email: &'a str,
+}
+
+#[derive(Queryable, Serialize)]
+pub struct PushTargetQuery {
+    pub id: i64,
+    pub email: String,
+    /// "ntfy" or "gotify"
+    pub kind: String,
+    /// The ntfy topic URL, or the Gotify server URL
+    pub url: String,
+    /// Access token for ntfy, or application token for Gotify
+    #[serde(skip)]
+    pub token: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = push_targets)]
+pub struct PushTarget<'a> {
+    pub email: &'a str,
+    pub kind: &'a str,
+    pub url: &'a str,
+    pub token: Option<&'a str>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = pending_push_targets)]
+pub struct PendingPushTarget<'a> {
+    /// Random, and carried by the emailed link
+    pub id: &'a str,
+    pub email: &'a str,
+    pub kind: &'a str,
+    pub url: &'a str,
+    pub token: Option<&'a str>,
+    pub valid_until: DateTime<Utc>,
 }
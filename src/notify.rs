@@ -0,0 +1,220 @@
+//  ff-node-monitor -- Monitoring for Freifunk nodes
+//  Copyright (C) 2018  Ralf Jung <post AT ralfj DOT de>
+//
+//  This program is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU Affero General Public License as published by
+//  the Free Software Foundation, either version 3 of the License, or
+//  (at your option) any later version.
+//
+//  This program is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//  GNU Affero General Public License for more details.
+//
+//  You should have received a copy of the GNU Affero General Public License
+//  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use rocket_dyn_templates::Template;
+use serde_json::json;
+
+use crate::alertmanager::AlertmanagerNotifier;
+use crate::config::Config;
+use crate::db::DbConn;
+use crate::email::EmailNotifier;
+use crate::matrix;
+use crate::models::NodeQuery;
+use crate::push::PushNotifier;
+use crate::telegram;
+use crate::util::Ctx;
+use crate::webhook::WebhookNotifier;
+
+/// A node changed its state
+pub struct Event<'a> {
+    /// The node, in its new state
+    pub node: &'a NodeQuery,
+    /// Whether the node was online before; nodes that did not exist yet count as offline
+    pub was_online: bool,
+    /// When the cron run saw the change
+    pub timestamp: DateTime<Utc>,
+    /// Where to find details about the node
+    pub node_url: String,
+}
+
+impl Event<'_> {
+    /// Whether the node is online now
+    pub fn online(&self) -> bool {
+        self.node.online
+    }
+
+    /// A short plain-text description, for channels that do not have their own format
+    pub fn text(&self, ctx: &Ctx<'_>) -> Result<String> {
+        let vals = ctx.config().template_vals(json!({
+            "node": self.node,
+            "node_url": self.node_url,
+        }))?;
+        let text = Template::show(ctx, "chat_notification", vals)
+            .context("failed to render chat_notification template")?;
+        Ok(text.trim_end().to_owned())
+    }
+}
+
+/// A notification channel.
+///
+/// Subscriptions do not depend on the channel: an email address monitors a node, and each channel
+/// knows how to reach the addresses that set it up (by mail, via their webhooks, their Matrix
+/// rooms, ...).  New channels only have to be added to [`notifiers`].
+#[rocket::async_trait]
+pub trait Notifier: Send + Sync {
+    /// Tell the `subscribers` (the email addresses monitoring the node) about the event.  This is
+    /// called for every change, also for nodes without subscribers.
+    async fn notify(
+        &self,
+        ctx: &Ctx<'_>,
+        db: &DbConn,
+        event: &Event<'_>,
+        subscribers: &[String],
+    ) -> Result<()>;
+
+    /// Called once all events of a cron run were passed to `notify`, for channels that send
+    /// them together
+    async fn finish(&self, _ctx: &Ctx<'_>) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// All the channels that are enabled in the configuration
+pub fn notifiers(config: &Config) -> Result<Vec<Box<dyn Notifier>>> {
+    let mut notifiers: Vec<Box<dyn Notifier>> = vec![
+        Box::new(EmailNotifier),
+        Box::new(WebhookNotifier::new()?),
+        Box::new(PushNotifier::new()?),
+    ];
+    if let Some(client) = matrix::Client::new(config)? {
+        notifiers.push(Box::new(client));
+    }
+    if let Some(client) = telegram::Client::new(config)? {
+        notifiers.push(Box::new(client));
+    }
+    if let Some(notifier) = AlertmanagerNotifier::new(config)? {
+        notifiers.push(Box::new(notifier));
+    }
+    Ok(notifiers)
+}
+
+/// Tell all `notifiers` about the `events` of a cron run, each given with its subscribers.  A
+/// notifier that fails is logged and skipped, so that one broken channel does not keep the other
+/// channels, or the notifications about other nodes, from going out.  Returns the number of
+/// failures.
+pub async fn dispatch(
+    ctx: &Ctx<'_>,
+    db: &DbConn,
+    notifiers: &[Box<dyn Notifier>],
+    events: &[(Event<'_>, Vec<String>)],
+) -> usize {
+    let mut failures = 0;
+    for (event, subscribers) in events {
+        for notifier in notifiers {
+            if let Err(e) = notifier.notify(ctx, db, event, subscribers).await {
+                rocket::error!("Notifying about node {} failed: {:#}", event.node.id, e);
+                failures += 1;
+            }
+        }
+    }
+    for notifier in notifiers {
+        if let Err(e) = notifier.finish(ctx).await {
+            rocket::error!("Finishing notifications failed: {:#}", e);
+            failures += 1;
+        }
+    }
+    failures
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use anyhow::bail;
+
+    use super::*;
+    use crate::db;
+
+    struct FailingNotifier;
+
+    #[rocket::async_trait]
+    impl Notifier for FailingNotifier {
+        async fn notify(&self, _: &Ctx<'_>, _: &DbConn, _: &Event<'_>, _: &[String]) -> Result<()> {
+            bail!("this channel is broken")
+        }
+    }
+
+    /// Stands in for email: remembers who it notified about which node
+    struct RecordingNotifier(Arc<Mutex<Vec<(String, String)>>>);
+
+    #[rocket::async_trait]
+    impl Notifier for RecordingNotifier {
+        async fn notify(
+            &self,
+            _: &Ctx<'_>,
+            _: &DbConn,
+            event: &Event<'_>,
+            subscribers: &[String],
+        ) -> Result<()> {
+            let mut sent = self.0.lock().unwrap();
+            for subscriber in subscribers {
+                sent.push((event.node.id.clone(), subscriber.clone()));
+            }
+            Ok(())
+        }
+    }
+
+    fn node(id: &str) -> NodeQuery {
+        NodeQuery {
+            id: id.to_owned(),
+            name: id.to_owned(),
+            online: false,
+            first_seen: None,
+            last_seen: None,
+            clients: None,
+            loadavg: None,
+            memory_usage: None,
+            rootfs_usage: None,
+            domain: None,
+        }
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs a PostgreSQL database in TEST_DATABASE_URL"]
+    async fn failing_notifier_does_not_stop_others() {
+        let client = db::test_rocket().await;
+        let ctx = Ctx::new(client.rocket());
+        let db = DbConn::get_one(client.rocket()).await.unwrap();
+
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let notifiers: Vec<Box<dyn Notifier>> = vec![
+            Box::new(FailingNotifier),
+            Box::new(RecordingNotifier(sent.clone())),
+        ];
+        let (a, b) = (node("a"), node("b"));
+        let event = |node| Event {
+            node,
+            was_online: true,
+            timestamp: Utc::now(),
+            node_url: String::new(),
+        };
+        let events = vec![
+            (event(&a), vec!["x@example.org".to_owned()]),
+            (event(&b), vec!["y@example.org".to_owned()]),
+        ];
+
+        assert_eq!(dispatch(&ctx, &db, &notifiers, &events).await, 2);
+        assert_eq!(
+            *sent.lock().unwrap(),
+            [
+                ("a".to_owned(), "x@example.org".to_owned()),
+                ("b".to_owned(), "y@example.org".to_owned()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,477 @@
+//  ff-node-monitor -- Monitoring for Freifunk nodes
+//  Copyright (C) 2018  Ralf Jung <post AT ralfj DOT de>
+//
+//  This program is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU Affero General Public License as published by
+//  the Free Software Foundation, either version 3 of the License, or
+//  (at your option) any later version.
+//
+//  This program is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//  GNU Affero General Public License for more details.
+//
+//  You should have received a copy of the GNU Affero General Public License
+//  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use rocket::FromFormField;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use url::Url;
+
+use crate::action::Signable;
+use crate::confirm::Confirmable;
+use crate::db::DbConn;
+use crate::email::EmailAddress;
+use crate::models::*;
+use crate::notify::{Event, Notifier};
+use crate::schema::*;
+use crate::util::{random_secret, Ctx};
+use crate::webhook;
+
+/// How often we try to publish a message, and how long we wait before each retry
+const RETRY_DELAYS: [u64; 3] = [0, 10, 60];
+
+/// The push services we can publish to
+#[derive(Serialize, Deserialize, PartialEq, Debug, Copy, Clone, FromFormField)]
+#[serde(rename_all = "lowercase")]
+pub enum PushKind {
+    Ntfy,
+    Gotify,
+}
+
+impl PushKind {
+    /// The name used for this kind in the database
+    pub fn as_str(self) -> &'static str {
+        match self {
+            PushKind::Ntfy => "ntfy",
+            PushKind::Gotify => "gotify",
+        }
+    }
+
+    fn from_str(kind: &str) -> Option<Self> {
+        match kind {
+            "ntfy" => Some(PushKind::Ntfy),
+            "gotify" => Some(PushKind::Gotify),
+            _ => None,
+        }
+    }
+}
+
+/// Emailed to an address to add a push target for that address, until the given time.  The
+/// target itself waits in the database (see [`create_pending`]), so that its token is not part of
+/// the email; `kind` and `url` are only here to be shown.
+#[derive(Serialize, Deserialize)]
+pub struct AddPushTarget {
+    pub email: EmailAddress,
+    pub pending_id: String,
+    pub kind: PushKind,
+    pub url: String,
+    /// Unix timestamp
+    pub valid_until: i64,
+}
+
+impl Signable for AddPushTarget {
+    const PURPOSE: &'static str = "push";
+}
+
+impl Confirmable for AddPushTarget {
+    const EMAIL_TEMPLATE: &'static str = "push_link";
+    const REQUESTED_TEMPLATE: &'static str = "push_requested";
+    const CONFIRM_TEMPLATE: &'static str = "push_confirm";
+
+    fn email(&self) -> &EmailAddress {
+        &self.email
+    }
+
+    fn valid_until(&self) -> i64 {
+        self.valid_until
+    }
+
+    fn template_vals(&self) -> serde_json::Value {
+        json!({
+            "email": self.email,
+            "kind": self.kind,
+            "url": self.url,
+        })
+    }
+}
+
+/// Add a push target for `email`.  Nothing happens if it already exists.
+pub async fn create(
+    db: &DbConn,
+    email: &EmailAddress,
+    kind: PushKind,
+    url: &str,
+    token: Option<&str>,
+) -> Result<()> {
+    if !webhook::is_valid_url(url) {
+        bail!("invalid push URL: {}", url);
+    }
+    let email = email.clone();
+    let url = url.to_owned();
+    let token = token.map(str::to_owned);
+    db.run(move |db| {
+        diesel::insert_into(push_targets::table)
+            .values(&PushTarget {
+                email: &email,
+                kind: kind.as_str(),
+                url: &url,
+                token: token.as_deref(),
+            })
+            .on_conflict_do_nothing()
+            .execute(db)
+    })
+    .await?;
+    Ok(())
+}
+
+/// Store a push target for `email` until the emailed link is followed.  Returns the ID that the
+/// link carries.
+pub async fn create_pending(
+    db: &DbConn,
+    email: &EmailAddress,
+    kind: PushKind,
+    url: &str,
+    token: Option<&str>,
+    valid_until: DateTime<Utc>,
+) -> Result<String> {
+    if !webhook::is_valid_url(url) {
+        bail!("invalid push URL: {}", url);
+    }
+    let id = random_secret()?;
+    let email = email.clone();
+    let url = url.to_owned();
+    let token = token.map(str::to_owned);
+    let id2 = id.clone();
+    db.run(move |db| {
+        // Clean up expired requests
+        diesel::delete(
+            pending_push_targets::table.filter(pending_push_targets::valid_until.lt(Utc::now())),
+        )
+        .execute(db)?;
+        diesel::insert_into(pending_push_targets::table)
+            .values(&PendingPushTarget {
+                id: &id2,
+                email: &email,
+                kind: kind.as_str(),
+                url: &url,
+                token: token.as_deref(),
+                valid_until,
+            })
+            .execute(db)
+    })
+    .await?;
+    Ok(id)
+}
+
+/// Add the push target stored by [`create_pending`].  Nothing happens if it was already added
+/// (or has expired).
+pub async fn confirm_pending(db: &DbConn, email: &EmailAddress, id: &str) -> Result<()> {
+    let email = email.clone();
+    let id = id.to_owned();
+    db.run_transaction(move |db| {
+        let pending = diesel::delete(
+            pending_push_targets::table
+                .filter(pending_push_targets::id.eq(&id))
+                .filter(pending_push_targets::email.eq(&*email))
+                .filter(pending_push_targets::valid_until.ge(Utc::now())),
+        )
+        .returning((
+            pending_push_targets::kind,
+            pending_push_targets::url,
+            pending_push_targets::token,
+        ))
+        .get_result::<(String, String, Option<String>)>(db)
+        .optional()?;
+        if let Some((kind, url, token)) = pending {
+            diesel::insert_into(push_targets::table)
+                .values(&PushTarget {
+                    email: &email,
+                    kind: &kind,
+                    url: &url,
+                    token: token.as_deref(),
+                })
+                .on_conflict_do_nothing()
+                .execute(db)?;
+        }
+        Ok(())
+    })
+    .await
+}
+
+/// The push targets of `email`, newest first
+pub async fn list(db: &DbConn, email: &EmailAddress) -> Result<Vec<PushTargetQuery>> {
+    let email = email.clone();
+    Ok(db
+        .run(move |db| {
+            push_targets::table
+                .filter(push_targets::email.eq(&*email))
+                .order_by(push_targets::id.desc())
+                .load::<PushTargetQuery>(db)
+        })
+        .await?)
+}
+
+/// Remove the push target with the given ID, if it belongs to `email`.  Returns whether it
+/// existed.
+pub async fn remove(db: &DbConn, email: &EmailAddress, id: i64) -> Result<bool> {
+    let email = email.clone();
+    let num_deleted = db
+        .run(move |db| {
+            diesel::delete(
+                push_targets::table
+                    .filter(push_targets::id.eq(id))
+                    .filter(push_targets::email.eq(&*email)),
+            )
+            .execute(db)
+        })
+        .await?;
+    Ok(num_deleted > 0)
+}
+
+/// The push targets of the given subscribers
+pub async fn for_subscribers(db: &DbConn, subscribers: &[String]) -> Result<Vec<PushTargetQuery>> {
+    let subscribers = subscribers.to_vec();
+    Ok(db
+        .run(move |db| {
+            push_targets::table
+                .filter(push_targets::email.eq_any(subscribers))
+                .load::<PushTargetQuery>(db)
+        })
+        .await?)
+}
+
+/// A push notification about a node
+struct PushMessage {
+    title: String,
+    text: String,
+    /// Link to open when the notification is tapped
+    click_url: String,
+    /// Whether this is urgent (a node went offline)
+    high_priority: bool,
+}
+
+/// Build the request publishing `message` to `target`
+fn request(
+    client: &reqwest::Client,
+    target: &PushTargetQuery,
+    message: &PushMessage,
+) -> Result<reqwest::RequestBuilder> {
+    let kind = match PushKind::from_str(&target.kind) {
+        Some(kind) => kind,
+        None => bail!("unknown push target kind: {}", target.kind),
+    };
+    let request = match kind {
+        PushKind::Ntfy => {
+            // Headers cannot reliably carry non-ASCII titles, so we use query parameters
+            let mut url = Url::parse(&target.url)?;
+            url.query_pairs_mut()
+                .append_pair("title", &message.title)
+                .append_pair(
+                    "priority",
+                    if message.high_priority {
+                        "high"
+                    } else {
+                        "default"
+                    },
+                )
+                .append_pair("click", &message.click_url);
+            client.post(url).body(message.text.clone())
+        }
+        PushKind::Gotify => {
+            let mut url = Url::parse(&target.url)?;
+            url.path_segments_mut()
+                .map_err(|()| anyhow::anyhow!("invalid Gotify URL: {}", target.url))?
+                .pop_if_empty()
+                .push("message");
+            client.post(url).json(&json!({
+                "title": message.title,
+                "message": message.text,
+                // Gotify clients show priorities from 8 on as urgent
+                "priority": if message.high_priority { 8 } else { 5 },
+                "extras": {
+                    "client::notification": { "click": { "url": message.click_url } },
+                },
+            }))
+        }
+    };
+    Ok(match (kind, &target.token) {
+        (PushKind::Ntfy, Some(token)) => request.bearer_auth(token),
+        (PushKind::Gotify, Some(token)) => request.header("X-Gotify-Key", token),
+        (_, None) => request,
+    })
+}
+
+/// Publish `message` to the push target in the background, retrying a few times on failure
+fn deliver(client: &reqwest::Client, target: &PushTargetQuery, message: &PushMessage) {
+    let id = target.id;
+    // The URL may have been added before the current checks
+    if !webhook::is_valid_url(&target.url) {
+        return rocket::error!("Not publishing to push target {}: URL is not allowed", id);
+    }
+    let request = match request(client, target, message) {
+        Ok(request) => request,
+        Err(e) => return rocket::error!("Publishing to push target {} failed: {}", id, e),
+    };
+    rocket::tokio::spawn(async move {
+        for delay in RETRY_DELAYS {
+            rocket::tokio::time::sleep(Duration::from_secs(delay)).await;
+            let response = request
+                .try_clone()
+                .expect("push requests have a fixed body")
+                .send()
+                .await
+                .and_then(|response| response.error_for_status());
+            match response {
+                Ok(_) => return,
+                Err(e) => rocket::warn!("Publishing to push target {} failed: {}", id, e),
+            }
+        }
+        rocket::error!("Giving up publishing to push target {}", id);
+    });
+}
+
+/// Notifications via ntfy or Gotify
+pub struct PushNotifier {
+    client: reqwest::Client,
+}
+
+impl PushNotifier {
+    pub fn new() -> Result<Self> {
+        // The webhook client also suits push targets, which are just as user-controlled
+        Ok(PushNotifier {
+            client: webhook::client()?,
+        })
+    }
+}
+
+#[rocket::async_trait]
+impl Notifier for PushNotifier {
+    async fn notify(
+        &self,
+        ctx: &Ctx<'_>,
+        db: &DbConn,
+        event: &Event<'_>,
+        subscribers: &[String],
+    ) -> Result<()> {
+        let targets = for_subscribers(db, subscribers).await?;
+        if targets.is_empty() {
+            return Ok(());
+        }
+        let message = PushMessage {
+            title: ctx.config().ui.instance_name.clone(),
+            text: event.text(ctx)?,
+            click_url: event.node_url.clone(),
+            high_priority: !event.online(),
+        };
+        // Deliveries happen in the background
+        for target in targets {
+            deliver(&self.client, &target, &message);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target(kind: &str, url: &str, token: Option<&str>) -> PushTargetQuery {
+        PushTargetQuery {
+            id: 1,
+            email: "a@example.org".to_owned(),
+            kind: kind.to_owned(),
+            url: url.to_owned(),
+            token: token.map(str::to_owned),
+            created_at: Utc::now(),
+        }
+    }
+
+    fn message(online: bool) -> PushMessage {
+        PushMessage {
+            title: "Knotenüberwachung".to_owned(),
+            text: format!("Knoten ist {}", if online { "online" } else { "offline" }),
+            click_url: "https://example.org/node/0123456789ab".to_owned(),
+            high_priority: !online,
+        }
+    }
+
+    fn build(target: &PushTargetQuery, message: &PushMessage) -> reqwest::Request {
+        request(&reqwest::Client::new(), target, message)
+            .unwrap()
+            .build()
+            .unwrap()
+    }
+
+    fn body(request: &reqwest::Request) -> &[u8] {
+        request.body().unwrap().as_bytes().unwrap()
+    }
+
+    #[test]
+    fn ntfy_request() {
+        let topic = target("ntfy", "https://ntfy.example.org/topic", Some("tk_secret"));
+        for (online, priority) in [(false, "high"), (true, "default")] {
+            let request = build(&topic, &message(online));
+            assert_eq!(request.method(), reqwest::Method::POST);
+            let url = request.url();
+            assert_eq!(url.path(), "/topic");
+            let query: Vec<_> = url.query_pairs().into_owned().collect();
+            assert_eq!(
+                query,
+                [
+                    ("title".to_owned(), "Knotenüberwachung".to_owned()),
+                    ("priority".to_owned(), priority.to_owned()),
+                    (
+                        "click".to_owned(),
+                        "https://example.org/node/0123456789ab".to_owned()
+                    ),
+                ]
+            );
+            assert_eq!(request.headers()["authorization"], "Bearer tk_secret");
+            assert_eq!(body(&request), message(online).text.as_bytes());
+        }
+
+        // Public topics need no token
+        let public = target("ntfy", "https://ntfy.sh/topic", None);
+        let request = build(&public, &message(true));
+        assert!(request.headers().get("authorization").is_none());
+    }
+
+    #[test]
+    fn gotify_request() {
+        let server = target("gotify", "https://example.org/gotify/", Some("app-token"));
+        for (online, priority) in [(false, 8), (true, 5)] {
+            let request = build(&server, &message(online));
+            assert_eq!(request.method(), reqwest::Method::POST);
+            assert_eq!(request.url().as_str(), "https://example.org/gotify/message");
+            assert_eq!(request.headers()["x-gotify-key"], "app-token");
+            assert_eq!(request.headers()["content-type"], "application/json");
+            let body: serde_json::Value = serde_json::from_slice(body(&request)).unwrap();
+            assert_eq!(
+                body,
+                json!({
+                    "title": "Knotenüberwachung",
+                    "message": message(online).text,
+                    "priority": priority,
+                    "extras": {
+                        "client::notification": {
+                            "click": { "url": "https://example.org/node/0123456789ab" },
+                        },
+                    },
+                })
+            );
+        }
+    }
+
+    #[test]
+    fn reject_unknown_kind() {
+        let unknown = target("pushover", "https://example.org/", None);
+        assert!(request(&reqwest::Client::new(), &unknown, &message(false)).is_err());
+    }
+}
@@ -0,0 +1,171 @@
+//  ff-node-monitor -- Monitoring for Freifunk nodes
+//  Copyright (C) 2018  Ralf Jung <post AT ralfj DOT de>
+//
+//  This program is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU Affero General Public License as published by
+//  the Free Software Foundation, either version 3 of the License, or
+//  (at your option) any later version.
+//
+//  This program is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//  GNU Affero General Public License for more details.
+//
+//  You should have received a copy of the GNU Affero General Public License
+//  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::net::IpAddr;
+
+use anyhow::Result;
+use chrono::{Duration, Utc};
+use diesel::prelude::*;
+use diesel::result::{DatabaseErrorKind, Error::DatabaseError};
+
+use crate::config::Limit;
+use crate::db::DbConn;
+use crate::email::EmailAddress;
+use crate::models::*;
+use crate::schema::*;
+use crate::util::{self, Ctx};
+
+impl<'r> Ctx<'r> {
+    /// Check whether we may send another email to `to` on behalf of `client_ip`.
+    /// If yes, this counts as sending the email.
+    pub async fn rate_limit(
+        &self,
+        db: &DbConn,
+        to: &EmailAddress,
+        client_ip: Option<IpAddr>,
+    ) -> Result<bool> {
+        let limits = &self.config().rate_limits;
+        let mut keys: Vec<(&'static str, String, Limit)> = vec![(
+            "email",
+            util::hash_personal_data(&self.config().secrets, to),
+            limits.per_email,
+        )];
+        if let Some(client_ip) = client_ip {
+            keys.push((
+                "ip",
+                util::hash_personal_data(&self.config().secrets, &client_ip.to_string()),
+                limits.per_ip,
+            ));
+        }
+        Ok(db.run(move |db| check_and_record(db, &keys)).await?)
+    }
+}
+
+/// How often we retry when concurrent requests conflict
+const MAX_ATTEMPTS: usize = 10;
+
+/// Check the given limits, and if none is exceeded, record an event for each of them.  This runs
+/// in a serializable transaction, so that concurrent requests cannot all see the count below the
+/// limit and then all go ahead.
+fn check_and_record(
+    db: &mut PgConnection,
+    keys: &[(&'static str, String, Limit)],
+) -> QueryResult<bool> {
+    let mut attempt = 1;
+    loop {
+        let result = db
+            .build_transaction()
+            .serializable()
+            .run(|db| try_check_and_record(db, keys));
+        match result {
+            Err(DatabaseError(DatabaseErrorKind::SerializationFailure, _))
+                if attempt < MAX_ATTEMPTS =>
+            {
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+fn try_check_and_record(
+    db: &mut PgConnection,
+    keys: &[(&'static str, String, Limit)],
+) -> QueryResult<bool> {
+    let now = Utc::now();
+    let max_window = keys.iter().map(|(_, _, limit)| limit.window).max().unwrap();
+    // Forget about everything that is too old to matter for any limit
+    diesel::delete(
+        rate_limit_events::table
+            .filter(rate_limit_events::created_at.lt(now - Duration::seconds(max_window.into()))),
+    )
+    .execute(db)?;
+    // Check all the limits
+    for (kind, key, limit) in keys.iter() {
+        let count: i64 = rate_limit_events::table
+            .filter(rate_limit_events::kind.eq(kind))
+            .filter(rate_limit_events::key.eq(key))
+            .filter(rate_limit_events::created_at.gt(now - Duration::seconds(limit.window.into())))
+            .count()
+            .get_result(db)?;
+        if count >= limit.max {
+            return Ok(false);
+        }
+    }
+    // Record this event
+    let events: Vec<RateLimitEvent> = keys
+        .iter()
+        .map(|(kind, key, _)| RateLimitEvent { kind, key })
+        .collect();
+    diesel::insert_into(rate_limit_events::table)
+        .values(&events)
+        .execute(db)?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+
+    fn keys(key: &str, max: i64) -> Vec<(&'static str, String, Limit)> {
+        vec![("test", key.to_owned(), Limit { max, window: 60 })]
+    }
+
+    fn cleanup(db: &mut PgConnection, key: &str) {
+        diesel::delete(rate_limit_events::table.filter(rate_limit_events::key.eq(key)))
+            .execute(db)
+            .unwrap();
+    }
+
+    #[test]
+    #[ignore = "needs a PostgreSQL database in TEST_DATABASE_URL"]
+    fn limit_is_enforced() {
+        let mut db = db::test_connection();
+        let key = format!("limit-{}", util::random_secret().unwrap());
+        for _ in 0..3 {
+            assert!(check_and_record(&mut db, &keys(&key, 3)).unwrap());
+        }
+        assert!(!check_and_record(&mut db, &keys(&key, 3)).unwrap());
+        // A different key is not affected
+        let other = format!("{}-other", key);
+        assert!(check_and_record(&mut db, &keys(&other, 3)).unwrap());
+        cleanup(&mut db, &key);
+        cleanup(&mut db, &other);
+    }
+
+    #[test]
+    #[ignore = "needs a PostgreSQL database in TEST_DATABASE_URL"]
+    fn limit_holds_for_concurrent_requests() {
+        let key = format!("concurrent-{}", util::random_secret().unwrap());
+        let threads: Vec<_> = (0..6)
+            .map(|_| {
+                let key = key.clone();
+                std::thread::spawn(move || {
+                    let mut db = db::test_connection();
+                    check_and_record(&mut db, &keys(&key, 2)).unwrap()
+                })
+            })
+            .collect();
+        let allowed = threads
+            .into_iter()
+            .map(|thread| thread.join().unwrap())
+            .filter(|allowed| *allowed)
+            .count();
+        assert_eq!(allowed, 2);
+        cleanup(&mut db::test_connection(), &key);
+    }
+}
@@ -14,28 +14,68 @@
 //  You should have received a copy of the GNU Affero General Public License
 //  along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::time::Instant;
 
-use base64::Engine as _;
+use anyhow::Context;
+use chrono::{DateTime, Utc};
 use diesel::prelude::*;
-use rmp_serde::from_slice as deserialize_from_slice;
-use rmp_serde::to_vec as serialize_to_vec;
+use serde::Serialize;
 use serde_json::json;
 
-use rocket::{form::Form, response, State};
-use rocket::{get, post, routes, uri, Request};
+use rocket::http::{ContentType, CookieJar, Header, Status};
+use rocket::response::Redirect;
+use rocket::serde::json::Json;
+use rocket::{form::Form, response, Either, State};
+use rocket::{get, post, routes, uri, FromForm, Request, Responder};
 use rocket_dyn_templates::Template;
 
 use crate::action::*;
+use crate::apitoken::{self, IssueApiToken};
 use crate::config::Config;
 use crate::cron;
 use crate::db::DbConn;
 use crate::email::EmailAddress;
+use crate::export::DataExport;
+use crate::feed;
+use crate::health::Readiness;
+use crate::matrix;
+use crate::metrics::MetricsAccess;
 use crate::models::*;
-use crate::util::Ctx;
+use crate::push::{self, AddPushTarget, PushKind};
+use crate::session::{self, Admin, Login, Session};
+use crate::telegram;
+use crate::util::{hash_client_ip, random_secret, Ctx};
+use crate::webhook::{self, AddWebhook};
 
-const BASE64_ENGINE: base64::engine::GeneralPurpose =
-    base64::engine::general_purpose::URL_SAFE_NO_PAD;
+/// How long (in seconds) a link granting access to a protected list is valid
+const LIST_ACCESS_VALIDITY: i64 = 7 * 24 * 60 * 60;
+/// How long (in seconds) a login link is valid
+const LOGIN_VALIDITY: i64 = 24 * 60 * 60;
+/// How long (in seconds) a link to confirm moving the monitored nodes to another address is valid
+const CHANGE_EMAIL_VALIDITY: i64 = 24 * 60 * 60;
+/// How long (in seconds) a link to create an API token is valid
+const API_TOKEN_LINK_VALIDITY: i64 = 24 * 60 * 60;
+/// How long (in seconds) a link to add a webhook is valid
+const WEBHOOK_LINK_VALIDITY: i64 = 24 * 60 * 60;
+/// How long (in seconds) a link to add a push target is valid
+const PUSH_LINK_VALIDITY: i64 = 24 * 60 * 60;
+/// How many audit log entries to show at most
+const AUDIT_LOG_LIMIT: i64 = 200;
+/// How many online/offline transitions to show on the node page
+const NODE_EVENTS_LIMIT: i64 = 20;
+/// Over how many days to count outages on the node page
+const NODE_OUTAGE_DAYS: i64 = 30;
+/// How many hours of state changes to show on the status page
+const STATUS_CHANGES_HOURS: i64 = 24;
+/// How many state changes to show on the status page at most
+const STATUS_CHANGES_LIMIT: i64 = 50;
+/// How many entries a feed has at most
+const FEED_LIMIT: i64 = 50;
+/// How long (in seconds) clients may cache a badge; the node list is usually updated every five
+/// minutes
+const BADGE_MAX_AGE: u32 = 5 * 60;
 
 /// Custom error type to allow using `?` below.
 struct Error(anyhow::Error);
@@ -62,10 +102,109 @@ fn index(ctx: Ctx<'_>) -> Result<Template> {
     Ok(ctx.template("index", json!({}))?)
 }
 
-#[get("/list?<email>")]
-async fn list(email: EmailAddress, ctx: Ctx<'_>, db: DbConn) -> Result<Template> {
+/// Compute the URL of the list for `email`.  With `grant_access`, the URL also grants access to
+/// the list when it is protected, so only send such URLs to `email` itself.
+pub fn list_url(config: &Config, email: &EmailAddress, grant_access: bool) -> String {
+    let access = (grant_access && config.ui.protect_list).then(|| {
+        let access = ListAccess {
+            email: email.clone(),
+            valid_until: Utc::now().timestamp() + LIST_ACCESS_VALIDITY,
+        };
+        Signed::sign(access, &config.secrets).encode()
+    });
+    config
+        .urls
+        .absolute(uri!(list(email = email, access = access)))
+}
+
+/// Compute the URL for removing all nodes monitored by `email`.  Only send this to `email` itself.
+pub fn unsubscribe_url(config: &Config, email: &EmailAddress) -> String {
+    let unsubscribe = UnsubscribeAll {
+        email: email.clone(),
+    };
+    let token = Signed::sign(unsubscribe, &config.secrets).encode();
+    config.urls.absolute(uri!(unsubscribe(token = &token)))
+}
+
+/// Compute the URL of the feed of all nodes monitored by `email`, given its current feed secret.
+/// Only show this to `email` itself.
+pub fn feed_url(config: &Config, email: &EmailAddress, secret: String) -> String {
+    let feed = SubscriberFeed {
+        email: email.clone(),
+        secret,
+    };
+    let token = Signed::sign(feed, &config.secrets).encode();
+    config.urls.absolute(uri!(subscriber_feed(token = &token)))
+}
+
+/// Compute the URL of the page for the node with the given ID.
+pub fn node_url(config: &Config, id: &str) -> String {
+    config.urls.absolute(uri!(node(id = id)))
+}
+
+pub fn check_list_access(config: &Config, email: &EmailAddress, access: &str) -> bool {
+    let access = match Signed::<ListAccess>::decode(access) {
+        Ok(access) => access.verify(&config.secrets),
+        Err(_) => return false,
+    };
+    match access {
+        Ok(access) => *access.email == **email && access.valid_until > Utc::now().timestamp(),
+        Err(_) => false,
+    }
+}
+
+#[get("/list?<email>&<access>")]
+async fn list(
+    email: EmailAddress,
+    access: Option<String>,
+    session: Option<Session>,
+    ctx: Ctx<'_>,
+    db: DbConn,
+) -> Result<Template> {
     use crate::schema::*;
 
+    let logged_in = session.is_some_and(|session| session.is_for(&email));
+    if ctx.config().ui.protect_list && !logged_in {
+        let has_access = access
+            .as_deref()
+            .is_some_and(|access| check_list_access(ctx.config(), &email, access));
+        if !has_access {
+            return Ok(ctx.template(
+                "list_locked",
+                json!({
+                    "email": email,
+                    "expired": access.is_some(),
+                }),
+            )?);
+        }
+    }
+
+    // A logged-in user can remove all nodes directly, and see all their data
+    let unsubscribe_url = logged_in.then(|| unsubscribe_url(ctx.config(), &email));
+    let export_url = ctx.config().urls.absolute(uri!(export(email = &email)));
+    let feed_url = if logged_in {
+        let feed_email = email.clone();
+        let secret = db.run(move |db| feed::secret(db, &feed_email)).await?;
+        Some(feed_url(ctx.config(), &email, secret))
+    } else {
+        None
+    };
+    let (api_tokens, webhooks, push_targets, matrix_rooms, matrix_pending, telegram_chats) =
+        if logged_in {
+            (
+                apitoken::list(&db, &email).await?,
+                webhook::list(&db, &email).await?,
+                push::list(&db, &email).await?,
+                matrix::list(&db, &email).await?,
+                matrix::list_pending(&db, &email).await?,
+                telegram::list(&db, &email).await?,
+            )
+        } else {
+            Default::default()
+        };
+    let matrix_enabled = ctx.config().matrix.is_some();
+    let telegram_enabled = ctx.config().telegram.is_some();
+
     let vars = db
         .run_transaction(move |db| {
             let watched_nodes = monitors::table
@@ -89,6 +228,18 @@ async fn list(email: EmailAddress, ctx: Ctx<'_>, db: DbConn) -> Result<Template>
             };
             Ok(json!({
                 "email": email,
+                "logged_in": logged_in,
+                "unsubscribe_url": unsubscribe_url,
+                "export_url": export_url,
+                "feed_url": feed_url,
+                "api_tokens": api_tokens,
+                "webhooks": webhooks,
+                "push_targets": push_targets,
+                "matrix_enabled": matrix_enabled,
+                "matrix_rooms": matrix_rooms,
+                "matrix_pending": matrix_pending,
+                "telegram_enabled": telegram_enabled,
+                "telegram_chats": telegram_chats,
                 "watched_nodes": watched_nodes,
                 "all_nodes": all_nodes,
             }))
@@ -103,117 +254,1481 @@ fn list_formfail(ctx: Ctx<'_>) -> Result<Template> {
     Ok(ctx.template("list_error", json!({}))?)
 }
 
-#[post("/prepare_action", data = "<action>")]
-async fn prepare_action(
-    action: Form<Action>,
-    config: &State<Config>,
+#[get("/node/<id>")]
+async fn node(id: String, ctx: Ctx<'_>, db: DbConn) -> Result<Template> {
+    use crate::schema::*;
+
+    let vars = db
+        .run_transaction(move |db| {
+            let node = nodes::table.find(&id).first::<NodeQuery>(db).optional()?;
+            let events = node_events::table
+                .filter(node_events::node.eq(&id))
+                .order_by(node_events::id.desc())
+                .limit(NODE_EVENTS_LIMIT)
+                .load::<NodeEventQuery>(db)?;
+            let outages: i64 = node_events::table
+                .filter(node_events::node.eq(&id))
+                .filter(node_events::online.eq(false))
+                .filter(
+                    node_events::created_at
+                        .gt(Utc::now() - chrono::Duration::days(NODE_OUTAGE_DAYS)),
+                )
+                .count()
+                .get_result(db)?;
+            Ok(json!({
+                "id": id,
+                "node": node,
+                "events": events,
+                "outages": outages,
+                "outage_days": NODE_OUTAGE_DAYS,
+            }))
+        })
+        .await?;
+
+    Ok(ctx.template("node", vars)?)
+}
+
+#[derive(Responder)]
+struct Cached<T> {
+    inner: T,
+    cache_control: Header<'static>,
+}
+
+impl<T> Cached<T> {
+    fn new(inner: T, max_age: u32) -> Self {
+        Cached {
+            inner,
+            cache_control: Header::new("Cache-Control", format!("public, max-age={}", max_age)),
+        }
+    }
+}
+
+/// The current state of a node, for embedding elsewhere
+#[derive(Serialize)]
+struct BadgeState {
+    id: String,
+    /// `None` if the node is unknown
+    name: Option<String>,
+    /// "online", "offline" or "unknown"
+    state: &'static str,
+    last_seen: Option<DateTime<Utc>>,
+}
+
+async fn badge_state(id: String, db: &DbConn) -> Result<BadgeState> {
+    use crate::schema::*;
+
+    let node = db
+        .run({
+            let id = id.clone();
+            move |db| nodes::table.find(id).first::<NodeQuery>(db).optional()
+        })
+        .await?;
+    Ok(match node {
+        Some(node) => BadgeState {
+            id,
+            state: if node.online { "online" } else { "offline" },
+            name: Some(node.name),
+            last_seen: node.last_seen,
+        },
+        None => BadgeState {
+            id,
+            name: None,
+            state: "unknown",
+            last_seen: None,
+        },
+    })
+}
+
+/// Rough width (in pixels) of `text` in the badge font
+fn badge_text_width(text: &str) -> usize {
+    text.chars().count() * 7 + 10
+}
+
+#[get("/node/<id>/badge.svg")]
+async fn node_badge(id: String, ctx: Ctx<'_>, db: DbConn) -> Result<Cached<Template>> {
+    let state = badge_state(id, &db).await?;
+    let label = state.name.clone().unwrap_or_else(|| state.id.clone());
+    let (label_width, state_width) = (badge_text_width(&label), badge_text_width(state.state));
+    let template = ctx.template(
+        "badge",
+        json!({
+            "label": label,
+            "state": state.state,
+            "label_width": label_width,
+            "state_width": state_width,
+            "width": label_width + state_width,
+            // Centers of the texts
+            "label_x": label_width / 2,
+            "state_x": label_width + state_width / 2,
+        }),
+    )?;
+    Ok(Cached::new(template, BADGE_MAX_AGE))
+}
+
+#[get("/node/<id>/badge.json")]
+async fn node_badge_json(id: String, db: DbConn) -> Result<Cached<Json<BadgeState>>> {
+    let state = badge_state(id, &db).await?;
+    Ok(Cached::new(Json(state), BADGE_MAX_AGE))
+}
+
+/// A state change as shown on the status page and in feeds
+#[derive(Serialize)]
+struct StatusChange {
+    event: NodeEventQuery,
+    /// Current name of the node, if it still exists
+    name: Option<String>,
+}
+
+#[get("/status")]
+async fn status(ctx: Ctx<'_>, db: DbConn) -> Result<Template> {
+    use crate::schema::*;
+    use diesel::dsl::count_star;
+
+    let vars = db
+        .run_transaction(move |db| {
+            let counts: HashMap<bool, i64> = nodes::table
+                .group_by(nodes::online)
+                .select((nodes::online, count_star()))
+                .load::<(bool, i64)>(db)?
+                .into_iter()
+                .collect();
+            let changes: Vec<StatusChange> = node_events::table
+                .left_join(nodes::table.on(nodes::id.eq(node_events::node)))
+                .filter(
+                    node_events::created_at
+                        .gt(Utc::now() - chrono::Duration::hours(STATUS_CHANGES_HOURS)),
+                )
+                .order_by(node_events::id.desc())
+                .limit(STATUS_CHANGES_LIMIT)
+                .select((node_events::all_columns, nodes::name.nullable()))
+                .load::<(NodeEventQuery, Option<String>)>(db)?
+                .into_iter()
+                .map(|(event, name)| StatusChange { event, name })
+                .collect();
+            let last_run = cron_runs::table
+                .order_by(cron_runs::id.desc())
+                .first::<CronRunQuery>(db)
+                .optional()?;
+            let last_success = cron_runs::table
+                .filter(cron_runs::outcome.eq("ok"))
+                .order_by(cron_runs::id.desc())
+                .first::<CronRunQuery>(db)
+                .optional()?;
+            let last_failure = cron_runs::table
+                .filter(cron_runs::outcome.ne("ok"))
+                .order_by(cron_runs::id.desc())
+                .first::<CronRunQuery>(db)
+                .optional()?;
+            Ok(json!({
+                "online": counts.get(&true).copied().unwrap_or(0),
+                "offline": counts.get(&false).copied().unwrap_or(0),
+                "changes": changes,
+                "changes_hours": STATUS_CHANGES_HOURS,
+                "failing": last_run.is_some_and(|run| run.outcome != "ok"),
+                "last_success": last_success,
+                "last_failure": last_failure,
+            }))
+        })
+        .await?;
+
+    Ok(ctx.template("status", vars)?)
+}
+
+/// Render a list of state changes (newest first) as Atom feed
+fn render_feed(
+    ctx: &Ctx<'_>,
+    title: String,
+    self_url: String,
+    alternate_url: String,
+    changes: Vec<StatusChange>,
+) -> Result<(ContentType, Template)> {
+    let updated = changes
+        .first()
+        .map_or_else(Utc::now, |change| change.event.created_at);
+    let template = ctx.template(
+        "feed",
+        json!({
+            "title": title,
+            "self_url": self_url,
+            "alternate_url": alternate_url,
+            "updated": updated,
+            "changes": changes,
+        }),
+    )?;
+    Ok((ContentType::new("application", "atom+xml"), template))
+}
+
+#[get("/node/<id>/feed")]
+async fn node_feed(id: String, ctx: Ctx<'_>, db: DbConn) -> Result<(ContentType, Template)> {
+    use crate::schema::*;
+
+    let (name, changes) = db
+        .run({
+            let id = id.clone();
+            move |db| -> QueryResult<_> {
+                let name = nodes::table
+                    .find(&id)
+                    .select(nodes::name)
+                    .first::<String>(db)
+                    .optional()?;
+                let changes = node_events::table
+                    .filter(node_events::node.eq(&id))
+                    .order_by(node_events::id.desc())
+                    .limit(FEED_LIMIT)
+                    .load::<NodeEventQuery>(db)?
+                    .into_iter()
+                    .map(|event| StatusChange {
+                        event,
+                        name: name.clone(),
+                    })
+                    .collect();
+                Ok((name, changes))
+            }
+        })
+        .await?;
+
+    let config = ctx.config();
+    render_feed(
+        &ctx,
+        format!(
+            "{}: {}",
+            config.ui.instance_name,
+            name.as_deref().unwrap_or(&id)
+        ),
+        config.urls.absolute(uri!(node_feed(id = &id))),
+        node_url(config, &id),
+        changes,
+    )
+}
+
+#[get("/feed?<token>")]
+async fn subscriber_feed(
+    token: String,
     ctx: Ctx<'_>,
     db: DbConn,
-) -> Result<Template> {
+) -> Result<Option<(ContentType, Template)>> {
     use crate::schema::*;
 
-    let action = action.into_inner();
+    let feed = Signed::<SubscriberFeed>::decode(&token)
+        .ok()
+        .and_then(|feed| feed.verify(&ctx.config().secrets).ok());
+    let feed = match feed {
+        Some(feed) => feed,
+        None => return Ok(None),
+    };
+    // The feed URL was revoked
+    if !feed::check(&db, &ctx.config().secrets, &feed).await? {
+        return Ok(None);
+    }
+    let email = feed.email;
 
-    // obtain bytes for signed action payload
-    let signed_action = action.clone().sign(&config.secrets.action_signing_key);
-    let signed_action = serialize_to_vec(&signed_action)?;
-    let signed_action = BASE64_ENGINE.encode(&signed_action);
+    let changes = db
+        .run({
+            let email = email.clone();
+            move |db| {
+                node_events::table
+                    .left_join(nodes::table.on(nodes::id.eq(node_events::node)))
+                    .filter(
+                        node_events::node.eq_any(
+                            monitors::table
+                                .filter(monitors::email.eq(&*email))
+                                .select(monitors::id),
+                        ),
+                    )
+                    .order_by(node_events::id.desc())
+                    .limit(FEED_LIMIT)
+                    .select((node_events::all_columns, nodes::name.nullable()))
+                    .load::<(NodeEventQuery, Option<String>)>(db)
+            }
+        })
+        .await?
+        .into_iter()
+        .map(|(event, name)| StatusChange { event, name })
+        .collect();
 
-    // compute some URLs
-    let action_url = config
-        .urls
-        .absolute(uri!(run_action(signed_action = &signed_action)));
-    let list_url = config.urls.absolute(uri!(list(email = &action.email)));
+    let config = ctx.config();
+    render_feed(
+        &ctx,
+        format!("{}: {}", config.ui.instance_name, &*email),
+        config.urls.absolute(uri!(subscriber_feed(token = &token))),
+        list_url(config, &email, false),
+        changes,
+    )
+    .map(Some)
+}
 
-    // obtain user-readable node name
-    let node = action.node.clone();
-    let node = db
+#[derive(FromForm)]
+struct LoginRequest {
+    email: EmailAddress,
+}
+
+#[post("/send_login_link", data = "<request>")]
+async fn send_login_link(
+    request: Form<LoginRequest>,
+    ctx: Ctx<'_>,
+    db: DbConn,
+    client_ip: Option<IpAddr>,
+) -> Result<Template> {
+    let email = request.into_inner().email;
+
+    // Make sure we are not used to flood someone's inbox
+    if !ctx.rate_limit(&db, &email, client_ip).await? {
+        return Ok(ctx.template(
+            "rate_limited",
+            json!({
+                "email": email,
+                "list_url": list_url(ctx.config(), &email, false),
+            }),
+        )?);
+    }
+
+    // Build and send email
+    let now = Utc::now().timestamp();
+    let login = Login {
+        email: email.clone(),
+        issued_at: now,
+        valid_until: now + LOGIN_VALIDITY,
+    };
+    let token = Signed::sign(login, &ctx.config().secrets).encode();
+    let login_url = ctx.config().urls.absolute(uri!(login(token = &token)));
+    ctx.email(
+        "login_link",
+        json!({
+            "email": email,
+            "login_url": login_url,
+            "unsubscribe_url": unsubscribe_url(ctx.config(), &email),
+        }),
+        &email,
+    )
+    .await?;
+
+    // Render
+    Ok(ctx.template("login_link_sent", json!({ "email": email }))?)
+}
+
+#[get("/login?<token>")]
+async fn login(
+    token: String,
+    ctx: Ctx<'_>,
+    db: DbConn,
+    cookies: &CookieJar<'_>,
+) -> Result<Either<Redirect, Template>> {
+    let login = Signed::<Login>::decode(&token)
+        .ok()
+        .and_then(|login| login.verify(&ctx.config().secrets).ok())
+        .filter(|login| login.valid_until > Utc::now().timestamp());
+    let login = match login {
+        Some(login) => login,
+        None => return Ok(Either::Right(ctx.template("run_action_error", json!({}))?)),
+    };
+    let issued_at = DateTime::from_timestamp(login.issued_at, 0).unwrap();
+    if session::was_reset(&db, &login.email, issued_at).await? {
+        return Ok(Either::Right(ctx.template("run_action_error", json!({}))?));
+    }
+    ctx.metrics().confirmation("login");
+
+    Session::start(ctx.config(), cookies, &login.email);
+    Ok(Either::Left(Redirect::to(list_url(
+        ctx.config(),
+        &login.email,
+        false,
+    ))))
+}
+
+#[post("/logout")]
+fn logout(ctx: Ctx<'_>, cookies: &CookieJar<'_>) -> Redirect {
+    Session::end(cookies);
+    Admin::end(cookies);
+    Redirect::to(ctx.config().urls.root.to_string())
+}
+
+/// Log out, also in all other browsers that are logged in as this address
+#[post("/logout_everywhere")]
+async fn logout_everywhere(
+    session: Session,
+    ctx: Ctx<'_>,
+    db: DbConn,
+    cookies: &CookieJar<'_>,
+) -> Result<Redirect> {
+    db.run(move |db| session::reset(db, &session.email)).await?;
+    Session::end(cookies);
+    Ok(Redirect::to(ctx.config().urls.root.to_string()))
+}
+
+#[derive(FromForm)]
+struct ResetFeedRequest {
+    email: EmailAddress,
+}
+
+/// Make the old feed URL stop working; the list then shows a new one
+#[post("/reset_feed", data = "<request>")]
+async fn reset_feed(
+    request: Form<ResetFeedRequest>,
+    session: Option<Session>,
+    ctx: Ctx<'_>,
+    db: DbConn,
+) -> Result<Redirect> {
+    // If the session expired in the mean time, the list will offer to log in again
+    if session.is_some_and(|session| session.is_for(&request.email)) {
+        let email = request.email.clone();
+        db.run(move |db| feed::reset(db, &email)).await?;
+    }
+    Ok(Redirect::to(list_url(ctx.config(), &request.email, false)))
+}
+
+/// A node as shown to the user when confirming an action
+#[derive(Serialize)]
+pub struct NamedNode {
+    id: String,
+    name: String,
+}
+
+/// Obtain user-readable names for the nodes the action is about.
+/// Also returns the nodes that cannot be added because they do not exist.
+pub async fn action_nodes(
+    action: &Action,
+    db: &DbConn,
+) -> anyhow::Result<(Vec<NamedNode>, Vec<String>)> {
+    use crate::schema::*;
+
+    let ids = action.nodes.clone();
+    let known_nodes: HashMap<String, String> = db
         .run(move |db| {
             nodes::table
-                .find(node.as_str())
-                .first::<NodeQuery>(db)
-                .optional()
+                .filter(nodes::id.eq_any(ids))
+                .load::<NodeQuery>(db)
         })
-        .await?;
-    let node_name = match node {
-        Some(node) => node.name,
-        None if action.op == Operation::Remove =>
-        // Allow removing dead nodes
-        {
-            action.node.clone()
-        }
-        None => {
+        .await?
+        .into_iter()
+        .map(|node| (node.id, node.name))
+        .collect();
+
+    let mut named_nodes = Vec::new();
+    let mut missing_nodes = Vec::new();
+    for id in action.nodes.iter() {
+        match known_nodes.get(id) {
+            Some(name) => named_nodes.push(NamedNode {
+                id: id.clone(),
+                name: name.clone(),
+            }),
+            // Allow removing dead nodes
+            None if action.op == Operation::Remove => named_nodes.push(NamedNode {
+                id: id.clone(),
+                name: id.clone(),
+            }),
             // Trying to add a non-existing node. Stop this.
-            return Ok(ctx.template(
+            None => missing_nodes.push(id.clone()),
+        }
+    }
+    Ok((named_nodes, missing_nodes))
+}
+
+/// Directly execute an action for a logged-in user
+#[post("/session_action", data = "<action>")]
+async fn session_action(
+    action: Form<Action>,
+    session: Option<Session>,
+    ctx: Ctx<'_>,
+    db: DbConn,
+    client_ip: Option<IpAddr>,
+) -> Result<Either<Redirect, Template>> {
+    let mut action = action.into_inner();
+    action.normalize();
+    let list_url = list_url(ctx.config(), &action.email, false);
+
+    // If the session expired in the mean time, the list will offer to log in again
+    if session.is_some_and(|session| session.is_for(&action.email)) {
+        let (_, missing_nodes) = action_nodes(&action, &db).await?;
+        if !missing_nodes.is_empty() {
+            return Ok(Either::Right(ctx.template(
                 "prepare_action_error",
                 json!({
-                    "action": action,
-                    "list_url": list_url.as_str(),
+                    "missing_nodes": missing_nodes,
+                    "list_url": list_url,
                 }),
-            )?);
+            )?));
         }
+        // Requested and confirmed in one go
+        let now = Utc::now();
+        let confirmation = Confirmation {
+            requested_at: Some(now),
+            confirmed_at: now,
+            via: "session",
+            client_ip_hash: hash_client_ip(&ctx.config().secrets, client_ip),
+        };
+        action.run(&db, confirmation).await?;
+        ctx.metrics().action(action.op);
+    }
+    Ok(Either::Left(Redirect::to(list_url)))
+}
+
+#[post("/prepare_action", data = "<action>")]
+async fn prepare_action(
+    action: Form<Action>,
+    config: &State<Config>,
+    ctx: Ctx<'_>,
+    db: DbConn,
+    client_ip: Option<IpAddr>,
+) -> Result<Template> {
+    let mut action = action.into_inner();
+    action.normalize();
+    let list_url = list_url(config, &action.email, false);
+
+    // obtain user-readable node names
+    let (nodes, missing_nodes) = action_nodes(&action, &db).await?;
+    if !missing_nodes.is_empty() {
+        return Ok(ctx.template(
+            "prepare_action_error",
+            json!({
+                "missing_nodes": missing_nodes,
+                "list_url": list_url.as_str(),
+            }),
+        )?);
+    }
+
+    // Make sure we are not used to flood someone's inbox
+    if !ctx.rate_limit(&db, &action.email, client_ip).await? {
+        return Ok(ctx.template(
+            "rate_limited",
+            json!({
+                "email": action.email,
+                "list_url": list_url.as_str(),
+            }),
+        )?);
+    }
+
+    send_confirmation(&ctx, &action, &nodes).await?;
+
+    // Render
+    Ok(ctx.template(
+        "prepare_action",
+        json!({
+            "action": action,
+            "nodes": nodes,
+            "list_url": list_url,
+        }),
+    )?)
+}
+
+/// Send the email asking to confirm `action`.  `nodes` are the nodes as determined by
+/// `action_nodes`.  The caller is responsible for rate-limiting.
+pub async fn send_confirmation(
+    ctx: &Ctx<'_>,
+    action: &Action,
+    nodes: &[NamedNode],
+) -> anyhow::Result<()> {
+    let config = ctx.config();
+    // obtain bytes for signed action payload
+    let pending_action = PendingAction {
+        action: action.clone(),
+        requested_at: Utc::now().timestamp(),
     };
+    let signed_action = Signed::sign(pending_action, &config.secrets).encode();
+    let action_url = config
+        .urls
+        .absolute(uri!(run_action(signed_action = &signed_action)));
 
-    // Build and send email
     ctx.email(
         "confirm_action",
         json!({
             "action": action,
-            "node_name": node_name,
-            "action_url": action_url.as_str(),
-            "list_url": list_url.as_str(),
+            "nodes": nodes,
+            "action_url": action_url,
+            "list_url": list_url(config, &action.email, true),
+            "unsubscribe_url": unsubscribe_url(config, &action.email),
         }),
         &action.email,
     )
-    .await?;
+    .await
+}
 
-    // Render
+#[get("/run_action?<signed_action>")]
+async fn run_action(
+    signed_action: String,
+    db: DbConn,
+    ctx: Ctx<'_>,
+    client_ip: Option<IpAddr>,
+) -> Result<Template> {
+    // Determine and verify action
+    let secrets = &ctx.config().secrets;
+    let action = SignedAction::decode(&signed_action)
+        .ok()
+        .and_then(|action| action.verify(secrets).ok())
+        .map(|pending| {
+            let requested_at = DateTime::from_timestamp(pending.requested_at, 0);
+            (pending.action, requested_at)
+        });
+    let (action, requested_at) = match action {
+        Some(a) => a,
+        None => return Ok(ctx.template("run_action_error", json!({}))?),
+    };
+
+    // Execute action
+    let confirmation = Confirmation::now(secrets, requested_at, "email", client_ip);
+    let outcomes = action.run(&db, confirmation).await?;
+    ctx.metrics().confirmation("action");
+    ctx.metrics().action(action.op);
+
+    // Render (whoever got here has the confirmation email, so they may see the list)
+    let list_url = list_url(ctx.config(), &action.email, true);
     Ok(ctx.template(
-        "prepare_action",
+        "run_action",
         json!({
             "action": action,
-            "node_name": node_name,
             "list_url": list_url,
+            "outcomes": outcomes,
         }),
     )?)
 }
 
-#[get("/run_action?<signed_action>")]
-async fn run_action(signed_action: String, db: DbConn, ctx: Ctx<'_>) -> Result<Template> {
-    // Determine and verify action
-    let action: Result<Action> = (|| {
-        let signed_action = BASE64_ENGINE.decode(signed_action.as_str())?;
-        let signed_action: SignedAction = deserialize_from_slice(signed_action.as_slice())?;
-        Ok(signed_action
-            .verify(&ctx.config().secrets.action_signing_key)
-            .map_err(|_| anyhow::anyhow!("signature verification failed"))?)
-    })();
-    let action = match action {
-        Ok(a) => a,
-        Err(_) => return Ok(ctx.template("run_action_error", json!({}))?),
-    };
+#[derive(FromForm)]
+struct ChangeEmailRequest {
+    email: EmailAddress,
+    new_email: EmailAddress,
+}
 
-    // Execute action
-    let success = action.run(&db).await?;
+#[post("/prepare_change_email", data = "<request>")]
+async fn prepare_change_email(
+    request: Form<ChangeEmailRequest>,
+    ctx: Ctx<'_>,
+    db: DbConn,
+    client_ip: Option<IpAddr>,
+) -> Result<Either<Redirect, Template>> {
+    let request = request.into_inner();
+    let list_url = list_url(ctx.config(), &request.email, false);
+    if *request.email == *request.new_email {
+        return Ok(Either::Left(Redirect::to(list_url)));
+    }
+
+    // Make sure we are not used to flood someone's inbox
+    if !ctx.rate_limit(&db, &request.email, client_ip).await? {
+        return Ok(Either::Right(ctx.template(
+            "rate_limited",
+            json!({
+                "email": request.email,
+                "list_url": list_url,
+            }),
+        )?));
+    }
+
+    // The old address has to confirm first
+    let change = ChangeEmail {
+        from: request.email.clone(),
+        to: request.new_email.clone(),
+        from_confirmed: false,
+        valid_until: Utc::now().timestamp() + CHANGE_EMAIL_VALIDITY,
+    };
+    let token = Signed::sign(change, &ctx.config().secrets).encode();
+    let change_url = ctx
+        .config()
+        .urls
+        .absolute(uri!(change_email(token = &token)));
+    ctx.email(
+        "change_email_from",
+        json!({
+            "from": request.email,
+            "to": request.new_email,
+            "change_url": change_url,
+            "unsubscribe_url": unsubscribe_url(ctx.config(), &request.email),
+        }),
+        &request.email,
+    )
+    .await?;
 
     // Render
-    let list_url = ctx
+    Ok(Either::Right(ctx.template(
+        "prepare_change_email",
+        json!({
+            "from": request.email,
+            "to": request.new_email,
+            "list_url": list_url,
+        }),
+    )?))
+}
+
+fn check_change_email(config: &Config, token: &str) -> Option<ChangeEmail> {
+    Signed::<ChangeEmail>::decode(token)
+        .ok()
+        .and_then(|change| change.verify(&config.secrets).ok())
+        .filter(|change| change.valid_until > Utc::now().timestamp())
+}
+
+#[get("/change_email?<token>")]
+fn change_email(token: String, ctx: Ctx<'_>) -> Result<Template> {
+    // Only show the confirmation form here; mail scanners like to follow links in emails
+    Ok(match check_change_email(ctx.config(), &token) {
+        Some(change) => ctx.template(
+            "change_email_confirm",
+            json!({
+                "from": change.from,
+                "to": change.to,
+                "from_confirmed": change.from_confirmed,
+                "token": token,
+            }),
+        ),
+        None => ctx.template("run_action_error", json!({})),
+    }?)
+}
+
+#[derive(FromForm)]
+struct ChangeEmailConfirmation {
+    token: String,
+}
+
+#[post("/change_email", data = "<request>")]
+async fn change_email_confirm(
+    request: Form<ChangeEmailConfirmation>,
+    ctx: Ctx<'_>,
+    db: DbConn,
+    client_ip: Option<IpAddr>,
+) -> Result<Template> {
+    let change = match check_change_email(ctx.config(), &request.token) {
+        Some(change) => change,
+        None => return Ok(ctx.template("run_action_error", json!({}))?),
+    };
+    ctx.metrics().confirmation("change-email");
+
+    if change.from_confirmed {
+        // Both addresses confirmed, so we can do this
+        let confirmation =
+            Confirmation::now(&ctx.config().secrets, None, "change-email", client_ip);
+        change.run(&db, confirmation).await?;
+        return Ok(ctx.template(
+            "change_email",
+            json!({
+                "from": change.from,
+                "to": change.to,
+                "done": true,
+                "list_url": list_url(ctx.config(), &change.to, true),
+            }),
+        )?);
+    }
+
+    // Now the new address has to confirm
+    if !ctx.rate_limit(&db, &change.to, client_ip).await? {
+        return Ok(ctx.template(
+            "rate_limited",
+            json!({
+                "email": change.to,
+                "list_url": list_url(ctx.config(), &change.from, true),
+            }),
+        )?);
+    }
+    let (from, to) = (change.from.clone(), change.to.clone());
+    let change = ChangeEmail {
+        from_confirmed: true,
+        valid_until: Utc::now().timestamp() + CHANGE_EMAIL_VALIDITY,
+        ..change
+    };
+    let token = Signed::sign(change, &ctx.config().secrets).encode();
+    let change_url = ctx
         .config()
         .urls
-        .absolute(uri!(list(email = &action.email)));
+        .absolute(uri!(change_email(token = &token)));
+    ctx.email(
+        "change_email_to",
+        json!({
+            "from": from,
+            "to": to,
+            "change_url": change_url,
+            "unsubscribe_url": unsubscribe_url(ctx.config(), &to),
+        }),
+        &to,
+    )
+    .await?;
+
     Ok(ctx.template(
-        "run_action",
+        "change_email",
         json!({
-            "action": action,
+            "from": from,
+            "to": to,
+            "done": false,
+            "list_url": list_url(ctx.config(), &from, true),
+        }),
+    )?)
+}
+
+#[get("/unsubscribe?<token>")]
+fn unsubscribe(token: String, ctx: Ctx<'_>) -> Result<Template> {
+    // Only show the confirmation form here; mail scanners like to follow links in emails
+    let unsubscribe = Signed::<UnsubscribeAll>::decode(&token)
+        .ok()
+        .and_then(|unsubscribe| unsubscribe.verify(&ctx.config().secrets).ok());
+    Ok(match unsubscribe {
+        Some(unsubscribe) => ctx.template(
+            "unsubscribe",
+            json!({
+                "email": unsubscribe.email,
+                "token": token,
+            }),
+        ),
+        None => ctx.template("run_action_error", json!({})),
+    }?)
+}
+
+#[derive(FromForm)]
+struct UnsubscribeRequest {
+    token: String,
+}
+
+#[post("/unsubscribe", data = "<request>")]
+async fn unsubscribe_confirm(
+    request: Form<UnsubscribeRequest>,
+    ctx: Ctx<'_>,
+    db: DbConn,
+    client_ip: Option<IpAddr>,
+) -> Result<Template> {
+    let unsubscribe = Signed::<UnsubscribeAll>::decode(&request.token)
+        .ok()
+        .and_then(|unsubscribe| unsubscribe.verify(&ctx.config().secrets).ok());
+    let unsubscribe = match unsubscribe {
+        Some(unsubscribe) => unsubscribe,
+        None => return Ok(ctx.template("run_action_error", json!({}))?),
+    };
+
+    let confirmation = Confirmation::now(&ctx.config().secrets, None, "unsubscribe", client_ip);
+    let removed = unsubscribe.run(&db, confirmation).await?;
+    ctx.metrics().confirmation("unsubscribe");
+
+    // Render
+    Ok(ctx.template(
+        "unsubscribed",
+        json!({
+            "email": unsubscribe.email,
+            "removed": removed,
+        }),
+    )?)
+}
+
+#[derive(Responder)]
+struct Download<T> {
+    inner: T,
+    disposition: Header<'static>,
+}
+
+#[derive(FromForm)]
+struct ApiTokenRequest {
+    email: EmailAddress,
+    #[field(validate = len(1..=100))]
+    label: String,
+}
+
+/// Create an API token: directly for a logged-in user, otherwise after confirmation by email
+#[post("/prepare_api_token", data = "<request>")]
+async fn prepare_api_token(
+    request: Form<ApiTokenRequest>,
+    session: Option<Session>,
+    ctx: Ctx<'_>,
+    db: DbConn,
+    client_ip: Option<IpAddr>,
+) -> Result<Template> {
+    let request = request.into_inner();
+    let list_url = list_url(ctx.config(), &request.email, false);
+
+    if session.is_some_and(|session| session.is_for(&request.email)) {
+        let token = apitoken::create(
+            &db,
+            &ctx.config().secrets,
+            &request.email,
+            &request.label,
+            None,
+        )
+        .await?;
+        return Ok(ctx.template(
+            "api_token_created",
+            json!({
+                "email": request.email,
+                "label": request.label,
+                "token": token,
+                "list_url": list_url,
+            }),
+        )?);
+    }
+
+    let issue = IssueApiToken {
+        email: request.email,
+        label: request.label,
+        request_id: random_secret()?,
+        valid_until: Utc::now().timestamp() + API_TOKEN_LINK_VALIDITY,
+    };
+    Ok(ctx
+        .request_confirmation(&db, client_ip, issue, |token| {
+            uri!(api_token(token = token))
+        })
+        .await?)
+}
+
+#[get("/api_token?<token>")]
+fn api_token(token: String, ctx: Ctx<'_>) -> Result<Template> {
+    Ok(ctx.confirmation_form::<IssueApiToken>(token)?)
+}
+
+#[derive(FromForm)]
+struct ApiTokenConfirmation {
+    token: String,
+}
+
+#[post("/api_token", data = "<confirmation>")]
+async fn api_token_confirm(
+    confirmation: Form<ApiTokenConfirmation>,
+    ctx: Ctx<'_>,
+    db: DbConn,
+) -> Result<Template> {
+    let issue = match ctx.check_confirmation::<IssueApiToken>(&confirmation.token) {
+        Some(issue) => issue,
+        None => return Ok(ctx.template("run_action_error", json!({}))?),
+    };
+    ctx.metrics().confirmation("api-token");
+
+    let token = apitoken::create(
+        &db,
+        &ctx.config().secrets,
+        &issue.email,
+        &issue.label,
+        Some(&issue.request_id),
+    )
+    .await?;
+    Ok(ctx.template(
+        "api_token_created",
+        json!({
+            "email": issue.email,
+            "label": issue.label,
+            "token": token,
+            "list_url": list_url(ctx.config(), &issue.email, true),
+        }),
+    )?)
+}
+
+#[derive(FromForm)]
+struct RevokeApiTokenRequest {
+    email: EmailAddress,
+    id: i64,
+}
+
+#[post("/revoke_api_token", data = "<request>")]
+async fn revoke_api_token(
+    request: Form<RevokeApiTokenRequest>,
+    session: Option<Session>,
+    ctx: Ctx<'_>,
+    db: DbConn,
+) -> Result<Redirect> {
+    // If the session expired in the mean time, the list will offer to log in again
+    if session.is_some_and(|session| session.is_for(&request.email)) {
+        apitoken::revoke(&db, &request.email, request.id).await?;
+    }
+    Ok(Redirect::to(list_url(ctx.config(), &request.email, false)))
+}
+
+#[derive(FromForm)]
+struct WebhookRequest {
+    email: EmailAddress,
+    #[field(validate = with(|url| webhook::is_valid_url(url), "invalid webhook URL"))]
+    url: String,
+}
+
+/// Add a webhook: directly for a logged-in user, otherwise after confirmation by email
+#[post("/prepare_webhook", data = "<request>")]
+async fn prepare_webhook(
+    request: Form<WebhookRequest>,
+    session: Option<Session>,
+    ctx: Ctx<'_>,
+    db: DbConn,
+    client_ip: Option<IpAddr>,
+) -> Result<Template> {
+    let request = request.into_inner();
+    let list_url = list_url(ctx.config(), &request.email, false);
+
+    if session.is_some_and(|session| session.is_for(&request.email)) {
+        let secret = webhook::create(&db, &request.email, &request.url).await?;
+        return Ok(ctx.template(
+            "webhook_created",
+            json!({
+                "email": request.email,
+                "url": request.url,
+                "secret": secret,
+                "signature_header": webhook::SIGNATURE_HEADER,
+                "list_url": list_url,
+            }),
+        )?);
+    }
+
+    let add = AddWebhook {
+        email: request.email,
+        url: request.url,
+        valid_until: Utc::now().timestamp() + WEBHOOK_LINK_VALIDITY,
+    };
+    Ok(ctx
+        .request_confirmation(&db, client_ip, add, |token| {
+            uri!(add_webhook(token = token))
+        })
+        .await?)
+}
+
+#[get("/webhook?<token>")]
+fn add_webhook(token: String, ctx: Ctx<'_>) -> Result<Template> {
+    Ok(ctx.confirmation_form::<AddWebhook>(token)?)
+}
+
+#[derive(FromForm)]
+struct WebhookConfirmation {
+    token: String,
+}
+
+#[post("/webhook", data = "<confirmation>")]
+async fn add_webhook_confirm(
+    confirmation: Form<WebhookConfirmation>,
+    ctx: Ctx<'_>,
+    db: DbConn,
+) -> Result<Template> {
+    let add = match ctx.check_confirmation::<AddWebhook>(&confirmation.token) {
+        Some(add) => add,
+        None => return Ok(ctx.template("run_action_error", json!({}))?),
+    };
+    ctx.metrics().confirmation("webhook");
+
+    let secret = webhook::create(&db, &add.email, &add.url).await?;
+    Ok(ctx.template(
+        "webhook_created",
+        json!({
+            "email": add.email,
+            "url": add.url,
+            "secret": secret,
+            "signature_header": webhook::SIGNATURE_HEADER,
+            "list_url": list_url(ctx.config(), &add.email, true),
+        }),
+    )?)
+}
+
+#[derive(FromForm)]
+struct RemoveWebhookRequest {
+    email: EmailAddress,
+    id: i64,
+}
+
+#[post("/remove_webhook", data = "<request>")]
+async fn remove_webhook(
+    request: Form<RemoveWebhookRequest>,
+    session: Option<Session>,
+    ctx: Ctx<'_>,
+    db: DbConn,
+) -> Result<Redirect> {
+    // If the session expired in the mean time, the list will offer to log in again
+    if session.is_some_and(|session| session.is_for(&request.email)) {
+        webhook::remove(&db, &request.email, request.id).await?;
+    }
+    Ok(Redirect::to(list_url(ctx.config(), &request.email, false)))
+}
+
+#[derive(FromForm)]
+struct PushTargetRequest {
+    email: EmailAddress,
+    kind: PushKind,
+    #[field(validate = with(|url| webhook::is_valid_url(url), "invalid push URL"))]
+    url: String,
+    token: Option<String>,
+}
+
+/// Add a push target: directly for a logged-in user, otherwise after confirmation by email
+#[post("/prepare_push", data = "<request>")]
+async fn prepare_push(
+    request: Form<PushTargetRequest>,
+    session: Option<Session>,
+    ctx: Ctx<'_>,
+    db: DbConn,
+    client_ip: Option<IpAddr>,
+) -> Result<Either<Redirect, Template>> {
+    let request = request.into_inner();
+    let list_url = list_url(ctx.config(), &request.email, false);
+    let token = request
+        .token
+        .map(|token| token.trim().to_owned())
+        .filter(|token| !token.is_empty());
+
+    if session.is_some_and(|session| session.is_for(&request.email)) {
+        push::create(
+            &db,
+            &request.email,
+            request.kind,
+            &request.url,
+            token.as_deref(),
+        )
+        .await?;
+        return Ok(Either::Left(Redirect::to(list_url)));
+    }
+
+    let valid_until = Utc::now() + chrono::Duration::seconds(PUSH_LINK_VALIDITY);
+    let pending_id = push::create_pending(
+        &db,
+        &request.email,
+        request.kind,
+        &request.url,
+        token.as_deref(),
+        valid_until,
+    )
+    .await?;
+    let add = AddPushTarget {
+        email: request.email,
+        pending_id,
+        kind: request.kind,
+        url: request.url,
+        valid_until: valid_until.timestamp(),
+    };
+    let page = ctx
+        .request_confirmation(&db, client_ip, add, |token| uri!(add_push(token = token)))
+        .await?;
+    Ok(Either::Right(page))
+}
+
+#[get("/push?<token>")]
+fn add_push(token: String, ctx: Ctx<'_>) -> Result<Template> {
+    Ok(ctx.confirmation_form::<AddPushTarget>(token)?)
+}
+
+#[derive(FromForm)]
+struct PushConfirmation {
+    token: String,
+}
+
+#[post("/push", data = "<confirmation>")]
+async fn add_push_confirm(
+    confirmation: Form<PushConfirmation>,
+    ctx: Ctx<'_>,
+    db: DbConn,
+) -> Result<Either<Redirect, Template>> {
+    let add = match ctx.check_confirmation::<AddPushTarget>(&confirmation.token) {
+        Some(add) => add,
+        None => return Ok(Either::Right(ctx.template("run_action_error", json!({}))?)),
+    };
+    ctx.metrics().confirmation("push");
+
+    push::confirm_pending(&db, &add.email, &add.pending_id).await?;
+    Ok(Either::Left(Redirect::to(list_url(
+        ctx.config(),
+        &add.email,
+        true,
+    ))))
+}
+
+#[derive(FromForm)]
+struct RemovePushTargetRequest {
+    email: EmailAddress,
+    id: i64,
+}
+
+#[post("/remove_push", data = "<request>")]
+async fn remove_push(
+    request: Form<RemovePushTargetRequest>,
+    session: Option<Session>,
+    ctx: Ctx<'_>,
+    db: DbConn,
+) -> Result<Redirect> {
+    // If the session expired in the mean time, the list will offer to log in again
+    if session.is_some_and(|session| session.is_for(&request.email)) {
+        push::remove(&db, &request.email, request.id).await?;
+    }
+    Ok(Redirect::to(list_url(ctx.config(), &request.email, false)))
+}
+
+#[derive(FromForm)]
+struct MatrixRoomRequest {
+    email: EmailAddress,
+    #[field(validate = with(|target| matrix::is_valid_target(target), "invalid Matrix target"))]
+    target: String,
+}
+
+/// Add a Matrix user or room to notify; only for logged-in users
+#[post("/add_matrix_room", data = "<request>")]
+async fn add_matrix_room(
+    request: Form<MatrixRoomRequest>,
+    session: Option<Session>,
+    ctx: Ctx<'_>,
+    db: DbConn,
+) -> Result<Either<Redirect, Template>> {
+    let request = request.into_inner();
+    let list_url = list_url(ctx.config(), &request.email, false);
+    // If the session expired in the mean time, the list will offer to log in again
+    if !session.is_some_and(|session| session.is_for(&request.email)) {
+        return Ok(Either::Left(Redirect::to(list_url)));
+    }
+    let client = match matrix::Client::new(ctx.config())? {
+        Some(client) => client,
+        None => return Ok(Either::Left(Redirect::to(list_url))),
+    };
+
+    match client.resolve(&request.target).await {
+        Ok(room_id) => {
+            let config = ctx.config();
+            let code = matrix::create(
+                &db,
+                &config.secrets,
+                &request.email,
+                &request.target,
+                &room_id,
+            )
+            .await?;
+            let vals = config.template_vals(json!({ "email": request.email, "code": code }))?;
+            let text = Template::show(&ctx, "matrix_confirmation", vals)
+                .context("failed to render matrix_confirmation template")?;
+            client.send(&room_id, text);
+            Ok(Either::Left(Redirect::to(list_url)))
+        }
+        Err(e) => {
+            rocket::warn!("{:#}", e);
+            Ok(Either::Right(ctx.template(
+                "matrix_error",
+                json!({
+                    "email": request.email,
+                    "target": request.target,
+                    "list_url": list_url,
+                }),
+            )?))
+        }
+    }
+}
+
+#[derive(FromForm)]
+struct ConfirmMatrixRoomRequest {
+    email: EmailAddress,
+    id: i64,
+    code: String,
+}
+
+/// Start notifying a Matrix room once the user entered the code we sent there
+#[post("/confirm_matrix_room", data = "<request>")]
+async fn confirm_matrix_room(
+    request: Form<ConfirmMatrixRoomRequest>,
+    session: Option<Session>,
+    ctx: Ctx<'_>,
+    db: DbConn,
+) -> Result<Either<Redirect, Template>> {
+    let list_url = list_url(ctx.config(), &request.email, false);
+    // If the session expired in the mean time, the list will offer to log in again
+    if !session.is_some_and(|session| session.is_for(&request.email)) {
+        return Ok(Either::Left(Redirect::to(list_url)));
+    }
+    let secrets = &ctx.config().secrets;
+    if matrix::confirm(&db, secrets, &request.email, request.id, &request.code).await? {
+        return Ok(Either::Left(Redirect::to(list_url)));
+    }
+    Ok(Either::Right(ctx.template(
+        "matrix_code_invalid",
+        json!({
+            "email": request.email,
             "list_url": list_url,
-            "success": success,
+        }),
+    )?))
+}
+
+#[derive(FromForm)]
+struct RemoveMatrixRoomRequest {
+    email: EmailAddress,
+    id: i64,
+}
+
+#[post("/remove_matrix_room", data = "<request>")]
+async fn remove_matrix_room(
+    request: Form<RemoveMatrixRoomRequest>,
+    session: Option<Session>,
+    ctx: Ctx<'_>,
+    db: DbConn,
+) -> Result<Redirect> {
+    // If the session expired in the mean time, the list will offer to log in again
+    if session.is_some_and(|session| session.is_for(&request.email)) {
+        matrix::remove(&db, &request.email, request.id).await?;
+    }
+    Ok(Redirect::to(list_url(ctx.config(), &request.email, false)))
+}
+
+#[derive(FromForm)]
+struct LinkTelegramRequest {
+    email: EmailAddress,
+}
+
+/// Send a logged-in user to the bot, with a token to connect their chat
+#[post("/link_telegram", data = "<request>")]
+async fn link_telegram(
+    request: Form<LinkTelegramRequest>,
+    session: Option<Session>,
+    ctx: Ctx<'_>,
+    db: DbConn,
+) -> Result<Redirect> {
+    // If the session expired in the mean time, the list will offer to log in again
+    if let Some(telegram) = &ctx.config().telegram {
+        if session.is_some_and(|session| session.is_for(&request.email)) {
+            let token = telegram::create_link(&db, &ctx.config().secrets, &request.email).await?;
+            return Ok(Redirect::to(telegram::link_url(telegram, &token)));
+        }
+    }
+    Ok(Redirect::to(list_url(ctx.config(), &request.email, false)))
+}
+
+#[derive(FromForm)]
+struct RemoveTelegramChatRequest {
+    email: EmailAddress,
+    id: i64,
+}
+
+#[post("/remove_telegram_chat", data = "<request>")]
+async fn remove_telegram_chat(
+    request: Form<RemoveTelegramChatRequest>,
+    session: Option<Session>,
+    ctx: Ctx<'_>,
+    db: DbConn,
+) -> Result<Redirect> {
+    // If the session expired in the mean time, the list will offer to log in again
+    if session.is_some_and(|session| session.is_for(&request.email)) {
+        telegram::remove(&db, &request.email, request.id).await?;
+    }
+    Ok(Redirect::to(list_url(ctx.config(), &request.email, false)))
+}
+
+/// Updates for our Telegram bot, delivered by Telegram
+#[post("/telegram", data = "<update>")]
+async fn telegram_update(
+    _auth: telegram::WebhookAuth,
+    update: Json<telegram::Update>,
+    ctx: Ctx<'_>,
+    db: DbConn,
+) {
+    // Telegram delivers the update again if we fail, which could run a command twice; so failures
+    // are only logged
+    let result = async {
+        // The guard made sure Telegram is configured
+        let client = telegram::Client::new(ctx.config())?.unwrap();
+        ctx.telegram_update(&db, &client, update.into_inner()).await
+    }
+    .await;
+    if let Err(e) = result {
+        rocket::error!("Handling Telegram update failed: {:#}", e);
+    }
+}
+
+/// Everything we know about the logged-in user, as JSON
+#[get("/export?<email>")]
+async fn export(
+    email: EmailAddress,
+    session: Option<Session>,
+    ctx: Ctx<'_>,
+    db: DbConn,
+) -> Result<Either<Download<Json<DataExport>>, Redirect>> {
+    if !session.is_some_and(|session| session.is_for(&email)) {
+        // The list will offer to log in
+        return Ok(Either::Right(Redirect::to(list_url(
+            ctx.config(),
+            &email,
+            false,
+        ))));
+    }
+
+    let export = DataExport::collect(&db, &ctx.config().secrets, email).await?;
+    Ok(Either::Left(Download {
+        inner: Json(export),
+        disposition: Header::new(
+            "Content-Disposition",
+            "attachment; filename=\"ff-node-monitor.json\"",
+        ),
+    }))
+}
+
+#[derive(FromForm)]
+struct AdminLogin {
+    token: String,
+}
+
+#[post("/admin/login", data = "<login>")]
+fn admin_login(
+    login: Form<AdminLogin>,
+    ctx: Ctx<'_>,
+    cookies: &CookieJar<'_>,
+) -> Result<Either<Redirect, Template>> {
+    if Admin::start(ctx.config(), cookies, &login.token) {
+        Ok(Either::Left(Redirect::to(uri!(admin_audit(
+            None::<&str>,
+            None::<&str>,
+            None::<&str>
+        )))))
+    } else {
+        Ok(Either::Right(
+            ctx.template("admin_login", json!({ "failed": true }))?,
+        ))
+    }
+}
+
+/// Search the audit log.  `email` matches substrings, `node` and `ip` must match exactly.
+#[get("/admin/audit?<email>&<node>&<ip>")]
+async fn admin_audit(
+    email: Option<String>,
+    node: Option<String>,
+    ip: Option<String>,
+    _admin: Admin,
+    ctx: Ctx<'_>,
+    db: DbConn,
+) -> Result<Template> {
+    use crate::schema::*;
+
+    let email = email.filter(|s| !s.trim().is_empty());
+    let node = node.filter(|s| !s.trim().is_empty());
+    let ip = ip.filter(|s| !s.trim().is_empty());
+    let filter = json!({ "email": email, "node": node, "ip": ip });
+    let ip_hash = match ip.as_deref().map(|ip| ip.trim().parse::<IpAddr>()) {
+        Some(Ok(ip)) => hash_client_ip(&ctx.config().secrets, Some(ip)),
+        Some(Err(_)) => {
+            return Ok(ctx.template(
+                "admin_audit",
+                json!({ "filter": filter, "invalid_ip": true, "entries": [] }),
+            )?)
+        }
+        None => None,
+    };
+
+    let entries = db
+        .run(move |db| {
+            let mut query = audit_log::table
+                .order_by(audit_log::id.desc())
+                .limit(AUDIT_LOG_LIMIT)
+                .into_boxed();
+            if let Some(email) = email {
+                // Escape the wildcards, we only want to search for substrings
+                let pattern = email
+                    .trim()
+                    .replace('\\', "\\\\")
+                    .replace('%', "\\%")
+                    .replace('_', "\\_");
+                query = query.filter(audit_log::email.ilike(format!("%{}%", pattern)));
+            }
+            if let Some(node) = node {
+                query = query.filter(audit_log::node.eq(node.trim().to_owned()));
+            }
+            if let Some(ip_hash) = ip_hash {
+                query = query.filter(audit_log::client_ip_hash.eq(ip_hash));
+            }
+            query.load::<AuditLogQuery>(db)
+        })
+        .await?;
+
+    Ok(ctx.template(
+        "admin_audit",
+        json!({
+            "filter": filter,
+            "invalid_ip": false,
+            "entries": entries,
+            "truncated": entries.len() as i64 == AUDIT_LOG_LIMIT,
         }),
     )?)
 }
 
+#[get("/admin/audit", rank = 2)]
+fn admin_audit_login(ctx: Ctx<'_>) -> Result<Template> {
+    Ok(ctx.template("admin_login", json!({ "failed": false }))?)
+}
+
 #[get("/cron")]
 async fn cron_route(db: DbConn, ctx: Ctx<'_>) -> Result<Template> {
-    Ok(match ctx.update_nodes(&db).await? {
+    let start = Instant::now();
+    let result = ctx.update_nodes(&db).await;
+    ctx.metrics().cron_run(&result, start.elapsed());
+    cron::record_run(&db, &result).await?;
+    Ok(match result? {
         cron::UpdateResult::NotEnoughOnline(online) => ctx.template(
             "cron_error",
             json!({
@@ -224,13 +1739,82 @@ async fn cron_route(db: DbConn, ctx: Ctx<'_>) -> Result<Template> {
     }?)
 }
 
+#[get("/metrics")]
+async fn metrics(
+    _access: MetricsAccess,
+    ctx: Ctx<'_>,
+    db: DbConn,
+) -> Result<(ContentType, String)> {
+    Ok((ContentType::Plain, ctx.render_metrics(&db).await?))
+}
+
+/// The process is up
+#[get("/healthz")]
+fn healthz() -> Json<serde_json::Value> {
+    Json(json!({ "ok": true }))
+}
+
+/// The service is able to do its job
+#[get("/readyz")]
+async fn readyz(ctx: Ctx<'_>, db: Option<DbConn>) -> (Status, Json<Readiness>) {
+    let readiness = ctx.check_readiness(db.as_ref()).await;
+    let status = if readiness.ready {
+        Status::Ok
+    } else {
+        Status::ServiceUnavailable
+    };
+    (status, Json(readiness))
+}
+
 pub fn routes() -> Vec<rocket::Route> {
     routes![
         index,
         list,
         list_formfail,
+        node,
+        node_feed,
+        node_badge,
+        node_badge_json,
+        subscriber_feed,
+        status,
+        send_login_link,
+        login,
+        logout,
+        logout_everywhere,
+        reset_feed,
+        session_action,
         prepare_action,
         run_action,
-        cron_route
+        prepare_change_email,
+        change_email,
+        change_email_confirm,
+        unsubscribe,
+        unsubscribe_confirm,
+        prepare_api_token,
+        api_token,
+        api_token_confirm,
+        revoke_api_token,
+        prepare_webhook,
+        add_webhook,
+        add_webhook_confirm,
+        remove_webhook,
+        prepare_push,
+        add_push,
+        add_push_confirm,
+        remove_push,
+        add_matrix_room,
+        confirm_matrix_room,
+        remove_matrix_room,
+        link_telegram,
+        remove_telegram_chat,
+        telegram_update,
+        export,
+        admin_login,
+        admin_audit,
+        admin_audit_login,
+        cron_route,
+        metrics,
+        healthz,
+        readyz
     ]
 }
@@ -1,9 +1,76 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    api_tokens (id) {
+        id -> Int8,
+        email -> Varchar,
+        token_hash -> Varchar,
+        label -> Varchar,
+        created_at -> Timestamptz,
+        last_used_at -> Nullable<Timestamptz>,
+        request_id -> Nullable<Varchar>,
+    }
+}
+
+diesel::table! {
+    audit_log (id) {
+        id -> Int8,
+        created_at -> Timestamptz,
+        email -> Varchar,
+        node -> Varchar,
+        action -> Varchar,
+        via -> Varchar,
+        client_ip_hash -> Nullable<Varchar>,
+    }
+}
+
+diesel::table! {
+    cron_runs (id) {
+        id -> Int8,
+        created_at -> Timestamptz,
+        outcome -> Varchar,
+        online_nodes -> Nullable<Int4>,
+        message -> Nullable<Varchar>,
+    }
+}
+
+diesel::table! {
+    feed_secrets (email) {
+        email -> Varchar,
+        secret -> Varchar,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    invalid_monitors (id, email) {
+        id -> Varchar,
+        email -> Varchar,
+        created_at -> Nullable<Timestamptz>,
+        confirmed_at -> Nullable<Timestamptz>,
+        confirmed_via -> Nullable<Varchar>,
+        removed_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    matrix_rooms (id) {
+        id -> Int8,
+        email -> Varchar,
+        target -> Varchar,
+        room_id -> Varchar,
+        created_at -> Timestamptz,
+        confirmation_code_hash -> Nullable<Varchar>,
+    }
+}
+
 diesel::table! {
     monitors (id, email) {
         id -> Varchar,
         email -> Varchar,
+        created_at -> Nullable<Timestamptz>,
+        confirmed_at -> Nullable<Timestamptz>,
+        confirmed_via -> Nullable<Varchar>,
     }
 }
 
@@ -12,7 +79,105 @@ diesel::table! {
         id -> Varchar,
         name -> Varchar,
         online -> Bool,
+        first_seen -> Nullable<Timestamptz>,
+        last_seen -> Nullable<Timestamptz>,
+        clients -> Nullable<Int4>,
+        loadavg -> Nullable<Float8>,
+        memory_usage -> Nullable<Float8>,
+        rootfs_usage -> Nullable<Float8>,
+        domain -> Nullable<Varchar>,
+    }
+}
+
+diesel::table! {
+    node_events (id) {
+        id -> Int8,
+        node -> Varchar,
+        online -> Bool,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    pending_push_targets (id) {
+        id -> Varchar,
+        email -> Varchar,
+        kind -> Varchar,
+        url -> Varchar,
+        token -> Nullable<Varchar>,
+        valid_until -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    push_targets (id) {
+        id -> Int8,
+        email -> Varchar,
+        kind -> Varchar,
+        url -> Varchar,
+        token -> Nullable<Varchar>,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    rate_limit_events (id) {
+        id -> Int8,
+        kind -> Varchar,
+        key -> Varchar,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    session_resets (email) {
+        email -> Varchar,
+        reset_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    telegram_chats (id) {
+        id -> Int8,
+        email -> Varchar,
+        chat_id -> Int8,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    telegram_links (token_hash) {
+        token_hash -> Varchar,
+        email -> Varchar,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    webhooks (id) {
+        id -> Int8,
+        email -> Varchar,
+        url -> Varchar,
+        secret -> Varchar,
+        created_at -> Timestamptz,
     }
 }
 
-diesel::allow_tables_to_appear_in_same_query!(monitors, nodes,);
+diesel::allow_tables_to_appear_in_same_query!(
+    api_tokens,
+    audit_log,
+    cron_runs,
+    feed_secrets,
+    invalid_monitors,
+    matrix_rooms,
+    monitors,
+    node_events,
+    nodes,
+    pending_push_targets,
+    push_targets,
+    rate_limit_events,
+    session_resets,
+    telegram_chats,
+    telegram_links,
+    webhooks,
+);
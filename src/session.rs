@@ -0,0 +1,212 @@
+//  ff-node-monitor -- Monitoring for Freifunk nodes
+//  Copyright (C) 2018  Ralf Jung <post AT ralfj DOT de>
+//
+//  This program is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU Affero General Public License as published by
+//  the Free Software Foundation, either version 3 of the License, or
+//  (at your option) any later version.
+//
+//  This program is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//  GNU Affero General Public License for more details.
+//
+//  You should have received a copy of the GNU Affero General Public License
+//  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use anyhow::Result;
+use chrono::{DateTime, Duration, Utc};
+use diesel::prelude::*;
+use rocket::{
+    http::{Cookie, CookieJar, SameSite, Status},
+    request::{self, FromRequest, Outcome},
+    Request,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::action::Signable;
+use crate::config::Config;
+use crate::db::DbConn;
+use crate::email::EmailAddress;
+use crate::schema::*;
+use crate::util::{hash_personal_data, secrets_equal};
+
+const SESSION_COOKIE: &str = "session";
+const ADMIN_COOKIE: &str = "admin";
+
+/// Emailed to an address to log in as that address, until the given time
+#[derive(Serialize, Deserialize)]
+pub struct Login {
+    pub email: EmailAddress,
+    /// Unix timestamp of when the login link was sent
+    pub issued_at: i64,
+    /// Unix timestamp
+    pub valid_until: i64,
+}
+
+impl Signable for Login {
+    const PURPOSE: &'static str = "login";
+}
+
+/// How long (in seconds) a session lasts
+const SESSION_MAX_AGE: i64 = 30 * 24 * 60 * 60;
+
+/// What we store in the session cookie
+#[derive(Serialize, Deserialize)]
+struct SessionCookie {
+    email: EmailAddress,
+    issued_at: DateTime<Utc>,
+}
+
+/// A request guard for a logged-in user.  The email address was verified with a login link.
+pub struct Session {
+    pub email: EmailAddress,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Session {
+    type Error = ();
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let cookie = request
+            .cookies()
+            .get_private(SESSION_COOKIE)
+            .and_then(|cookie| serde_json::from_str::<SessionCookie>(cookie.value()).ok())
+            .filter(|cookie| cookie.issued_at + Duration::seconds(SESSION_MAX_AGE) > Utc::now());
+        let cookie = match cookie {
+            Some(cookie) => cookie,
+            None => return Outcome::Forward(Status::Unauthorized),
+        };
+        let db = match request.guard::<DbConn>().await {
+            Outcome::Success(db) => db,
+            _ => return Outcome::Forward(Status::ServiceUnavailable),
+        };
+        match was_reset(&db, &cookie.email, cookie.issued_at).await {
+            Ok(false) => Outcome::Success(Session {
+                email: cookie.email,
+            }),
+            Ok(true) => Outcome::Forward(Status::Unauthorized),
+            Err(e) => {
+                rocket::error!("Failed to check session: {:#}", e);
+                Outcome::Forward(Status::ServiceUnavailable)
+            }
+        }
+    }
+}
+
+impl Session {
+    pub fn start(config: &Config, cookies: &CookieJar<'_>, email: &EmailAddress) {
+        let value = SessionCookie {
+            email: email.clone(),
+            issued_at: Utc::now(),
+        };
+        let cookie = Cookie::build((SESSION_COOKIE, serde_json::to_string(&value).unwrap()))
+            .max_age(rocket::time::Duration::seconds(SESSION_MAX_AGE))
+            .same_site(SameSite::Lax)
+            .secure(config.urls.root.scheme() == "https");
+        cookies.add_private(cookie);
+    }
+
+    /// The cookie [`Session::start`] sets, for logging in directly in tests
+    #[cfg(test)]
+    pub fn test_cookie(email: &EmailAddress) -> Cookie<'static> {
+        let value = SessionCookie {
+            email: email.clone(),
+            issued_at: Utc::now(),
+        };
+        Cookie::new(SESSION_COOKIE, serde_json::to_string(&value).unwrap())
+    }
+
+    pub fn end(cookies: &CookieJar<'_>) {
+        cookies.remove_private(SESSION_COOKIE);
+    }
+
+    /// Whether this session may act on behalf of `email`
+    pub fn is_for(&self, email: &EmailAddress) -> bool {
+        *self.email == **email
+    }
+}
+
+/// End all sessions of `email`.  Login links that were sent before also stop working.
+pub fn reset(db: &mut PgConnection, email: &str) -> QueryResult<()> {
+    let now = Utc::now();
+    diesel::insert_into(session_resets::table)
+        .values((
+            session_resets::email.eq(email),
+            session_resets::reset_at.eq(now),
+        ))
+        .on_conflict(session_resets::email)
+        .do_update()
+        .set(session_resets::reset_at.eq(now))
+        .execute(db)?;
+    Ok(())
+}
+
+/// Whether sessions of `email` were reset after `issued_at`
+pub async fn was_reset(
+    db: &DbConn,
+    email: &EmailAddress,
+    issued_at: DateTime<Utc>,
+) -> Result<bool> {
+    let email = email.clone();
+    let reset_at = db
+        .run(move |db| {
+            session_resets::table
+                .find(&*email)
+                .select(session_resets::reset_at)
+                .first::<DateTime<Utc>>(db)
+                .optional()
+        })
+        .await?;
+    Ok(reset_at.is_some_and(|reset_at| reset_at >= issued_at))
+}
+
+/// A request guard for the admin, who logged in with the `admin_token` from the config
+pub struct Admin;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Admin {
+    type Error = ();
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let config = request.rocket().state::<Config>().unwrap();
+        // The cookie has to match the current token, so changing the token logs out the admin
+        let token_hash = match Admin::token_hash(config) {
+            Some(token_hash) => token_hash,
+            None => return Outcome::Forward(Status::Unauthorized),
+        };
+        match request.cookies().get_private(ADMIN_COOKIE) {
+            Some(cookie) if secrets_equal(&config.secrets, cookie.value(), &token_hash) => {
+                Outcome::Success(Admin)
+            }
+            _ => Outcome::Forward(Status::Unauthorized),
+        }
+    }
+}
+
+impl Admin {
+    /// What the admin cookie contains: a keyed hash of the admin token, if there is one
+    fn token_hash(config: &Config) -> Option<String> {
+        let admin_token = config.secrets.admin_token.as_deref()?;
+        Some(hash_personal_data(&config.secrets, admin_token))
+    }
+
+    /// Log in as admin if `token` is the configured admin token.  Returns whether that worked.
+    pub fn start(config: &Config, cookies: &CookieJar<'_>, token: &str) -> bool {
+        let admin_token = match config.secrets.admin_token.as_deref() {
+            Some(admin_token) => admin_token,
+            None => return false,
+        };
+        if !secrets_equal(&config.secrets, token, admin_token) {
+            return false;
+        }
+        let token_hash = Admin::token_hash(config).unwrap();
+        let cookie = Cookie::build((ADMIN_COOKIE, token_hash))
+            .same_site(SameSite::Strict)
+            .secure(config.urls.root.scheme() == "https");
+        cookies.add_private(cookie);
+        true
+    }
+
+    pub fn end(cookies: &CookieJar<'_>) {
+        cookies.remove_private(ADMIN_COOKIE);
+    }
+}
@@ -0,0 +1,531 @@
+//  ff-node-monitor -- Monitoring for Freifunk nodes
+//  Copyright (C) 2018  Ralf Jung <post AT ralfj DOT de>
+//
+//  This program is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU Affero General Public License as published by
+//  the Free Software Foundation, either version 3 of the License, or
+//  (at your option) any later version.
+//
+//  This program is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//  GNU Affero General Public License for more details.
+//
+//  You should have received a copy of the GNU Affero General Public License
+//  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use chrono::Utc;
+use diesel::prelude::*;
+use rocket_dyn_templates::Template;
+use serde::Deserialize;
+use serde_json::json;
+use url::Url;
+
+use rocket::http::Status;
+use rocket::request::{self, FromRequest, Outcome};
+use rocket::Request;
+
+use crate::action::{Action, Confirmation, Operation};
+use crate::config::{self, Config, Secrets};
+use crate::db::DbConn;
+use crate::email::EmailAddress;
+use crate::models::*;
+use crate::notify::{Event, Notifier};
+use crate::routes;
+use crate::schema::*;
+use crate::util::{hash_personal_data, random_secret, secrets_equal, Ctx};
+
+/// Header with which Telegram proves that an update comes from them
+const SECRET_HEADER: &str = "X-Telegram-Bot-Api-Secret-Token";
+/// How long (in seconds) a link to connect a chat is valid
+const LINK_VALIDITY: i64 = 60 * 60;
+/// How often we try to send a message, and how long we wait before each retry
+const RETRY_DELAYS: [u64; 3] = [0, 10, 60];
+
+/// Talks to the Telegram Bot API
+#[derive(Clone)]
+pub struct Client {
+    http: reqwest::Client,
+    api_url: Url,
+    bot_token: String,
+}
+
+impl Client {
+    /// The client for the configured bot, if Telegram is enabled
+    pub fn new(config: &Config) -> Result<Option<Client>> {
+        let (telegram, bot_token) = match (&config.telegram, &config.secrets.telegram_bot_token) {
+            (Some(telegram), Some(bot_token)) => (telegram, bot_token),
+            _ => return Ok(None),
+        };
+        Ok(Some(Client {
+            http: reqwest::Client::builder()
+                .timeout(Duration::from_secs(10))
+                .build()?,
+            api_url: telegram.api_url.clone(),
+            bot_token: bot_token.clone(),
+        }))
+    }
+
+    /// Send a text message to the chat
+    pub async fn send_message(&self, chat_id: i64, text: &str) -> Result<()> {
+        let mut url = self.api_url.clone();
+        url.path_segments_mut()
+            .expect("the Bot API URL cannot be a base")
+            .pop_if_empty()
+            .push(&format!("bot{}", self.bot_token))
+            .push("sendMessage");
+        // Make sure errors do not mention the URL, it contains the bot token
+        self.http
+            .post(url)
+            .json(&json!({
+                "chat_id": chat_id,
+                "text": text.trim_end(),
+                "disable_web_page_preview": true,
+            }))
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| e.without_url())?;
+        Ok(())
+    }
+
+    /// Send a text message to the chat in the background, retrying a few times on failure
+    pub fn send(&self, chat_id: i64, text: String) {
+        let client = self.clone();
+        rocket::tokio::spawn(async move {
+            for delay in RETRY_DELAYS {
+                rocket::tokio::time::sleep(Duration::from_secs(delay)).await;
+                match client.send_message(chat_id, &text).await {
+                    Ok(()) => return,
+                    Err(e) => rocket::warn!("Sending to Telegram chat {} failed: {}", chat_id, e),
+                }
+            }
+            rocket::error!("Giving up sending to Telegram chat {}", chat_id);
+        });
+    }
+}
+
+/// Create a token with which a chat can be connected to `email`.  Only its hash is stored.
+pub async fn create_link(db: &DbConn, secrets: &Secrets, email: &EmailAddress) -> Result<String> {
+    let token = random_secret()?;
+    let token_hash = hash_personal_data(secrets, &token);
+    let email = email.clone();
+    db.run(move |db| {
+        // Clean up expired links
+        let expired = Utc::now() - chrono::Duration::seconds(LINK_VALIDITY);
+        diesel::delete(telegram_links::table.filter(telegram_links::created_at.lt(expired)))
+            .execute(db)?;
+        diesel::insert_into(telegram_links::table)
+            .values(&TelegramLink {
+                token_hash: &token_hash,
+                email: &email,
+            })
+            .execute(db)
+    })
+    .await?;
+    Ok(token)
+}
+
+/// The deep link that opens a chat with the bot and sends it `/start <token>`
+pub fn link_url(telegram: &config::Telegram, token: &str) -> String {
+    format!("https://t.me/{}?start={}", telegram.bot_name, token)
+}
+
+/// Connect the chat to the address the token was created for.  Returns that address, or `None`
+/// if the token is invalid or expired.
+async fn redeem_link(
+    db: &DbConn,
+    secrets: &Secrets,
+    token: &str,
+    chat_id: i64,
+) -> Result<Option<String>> {
+    let token_hash = hash_personal_data(secrets, token);
+    db.run_transaction(move |db| {
+        let valid_since = Utc::now() - chrono::Duration::seconds(LINK_VALIDITY);
+        let email = diesel::delete(
+            telegram_links::table
+                .filter(telegram_links::token_hash.eq(token_hash))
+                .filter(telegram_links::created_at.ge(valid_since)),
+        )
+        .returning(telegram_links::email)
+        .get_result::<String>(db)
+        .optional()?;
+        if let Some(email) = &email {
+            // A chat belongs to just one address
+            diesel::insert_into(telegram_chats::table)
+                .values(&TelegramChat { email, chat_id })
+                .on_conflict(telegram_chats::chat_id)
+                .do_update()
+                .set(telegram_chats::email.eq(email))
+                .execute(db)?;
+        }
+        Ok(email)
+    })
+    .await
+}
+
+/// The address the chat is connected to, if any
+async fn chat_email(db: &DbConn, chat_id: i64) -> Result<Option<EmailAddress>> {
+    let email = db
+        .run(move |db| {
+            telegram_chats::table
+                .filter(telegram_chats::chat_id.eq(chat_id))
+                .select(telegram_chats::email)
+                .get_result::<String>(db)
+                .optional()
+        })
+        .await?;
+    Ok(email.and_then(|email| EmailAddress::new(email).ok()))
+}
+
+/// Disconnect the chat.  Returns whether it was connected.
+async fn unlink_chat(db: &DbConn, chat_id: i64) -> Result<bool> {
+    let num_deleted = db
+        .run(move |db| {
+            diesel::delete(telegram_chats::table.filter(telegram_chats::chat_id.eq(chat_id)))
+                .execute(db)
+        })
+        .await?;
+    Ok(num_deleted > 0)
+}
+
+/// The chats connected to `email`, newest first
+pub async fn list(db: &DbConn, email: &EmailAddress) -> Result<Vec<TelegramChatQuery>> {
+    let email = email.clone();
+    Ok(db
+        .run(move |db| {
+            telegram_chats::table
+                .filter(telegram_chats::email.eq(&*email))
+                .order_by(telegram_chats::id.desc())
+                .load::<TelegramChatQuery>(db)
+        })
+        .await?)
+}
+
+/// Disconnect the chat with the given ID, if it belongs to `email`.  Returns whether it existed.
+pub async fn remove(db: &DbConn, email: &EmailAddress, id: i64) -> Result<bool> {
+    let email = email.clone();
+    let num_deleted = db
+        .run(move |db| {
+            diesel::delete(
+                telegram_chats::table
+                    .filter(telegram_chats::id.eq(id))
+                    .filter(telegram_chats::email.eq(&*email)),
+            )
+            .execute(db)
+        })
+        .await?;
+    Ok(num_deleted > 0)
+}
+
+/// The chats of the given subscribers
+pub async fn for_subscribers(
+    db: &DbConn,
+    subscribers: &[String],
+) -> Result<Vec<TelegramChatQuery>> {
+    let subscribers = subscribers.to_vec();
+    Ok(db
+        .run(move |db| {
+            telegram_chats::table
+                .filter(telegram_chats::email.eq_any(subscribers))
+                .load::<TelegramChatQuery>(db)
+        })
+        .await?)
+}
+
+/// A request guard making sure a request comes from Telegram
+pub struct WebhookAuth;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for WebhookAuth {
+    type Error = ();
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let config = request.rocket().state::<Config>().unwrap();
+        let secret = match config.secrets.telegram_webhook_secret.as_deref() {
+            Some(secret) if config.telegram.is_some() => secret,
+            _ => return Outcome::Error((Status::NotFound, ())),
+        };
+        match request.headers().get_one(SECRET_HEADER) {
+            Some(header) if secrets_equal(&config.secrets, header, secret) => {
+                Outcome::Success(WebhookAuth)
+            }
+            _ => Outcome::Error((Status::Unauthorized, ())),
+        }
+    }
+}
+
+/// The parts of an update from Telegram that we care about
+#[derive(Deserialize)]
+pub struct Update {
+    message: Option<Message>,
+}
+
+#[derive(Deserialize)]
+struct Message {
+    chat: Chat,
+    text: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Chat {
+    id: i64,
+}
+
+impl<'r> Ctx<'r> {
+    /// Handle a message sent to the bot, and reply to it
+    pub async fn telegram_update(
+        &self,
+        db: &DbConn,
+        client: &Client,
+        update: Update,
+    ) -> Result<()> {
+        let message = match update.message {
+            Some(message) => message,
+            None => return Ok(()),
+        };
+        let chat_id = message.chat.id;
+        let text = message.text.unwrap_or_default();
+        let mut words = text.split_whitespace();
+        // In groups, commands can be addressed to a specific bot as `/command@bot`
+        let command = words.next().unwrap_or("").split('@').next().unwrap();
+        let args: Vec<String> = words.map(str::to_owned).collect();
+
+        let reply = match command {
+            "/start" => match args.first() {
+                Some(token) => match redeem_link(db, &self.config().secrets, token, chat_id).await?
+                {
+                    Some(email) => json!({ "reply": "linked", "email": email }),
+                    None => json!({ "reply": "link_invalid" }),
+                },
+                None => json!({ "reply": "help" }),
+            },
+            "/watch" | "/unwatch" | "/list" | "/stop" => match chat_email(db, chat_id).await? {
+                Some(email) => {
+                    self.telegram_command(db, chat_id, command, email, args)
+                        .await?
+                }
+                None => json!({ "reply": "not_linked" }),
+            },
+            _ => json!({ "reply": "help" }),
+        };
+
+        let config = self.config();
+        let text = Template::show(self, "telegram_reply", config.template_vals(reply)?)
+            .context("failed to render telegram_reply template")?;
+        client.send_message(chat_id, &text).await
+    }
+
+    /// Run a command in a chat that is connected to `email`
+    async fn telegram_command(
+        &self,
+        db: &DbConn,
+        chat_id: i64,
+        command: &str,
+        email: EmailAddress,
+        args: Vec<String>,
+    ) -> Result<serde_json::Value> {
+        Ok(match command {
+            "/watch" | "/unwatch" => {
+                let op = if command == "/watch" {
+                    Operation::Add
+                } else {
+                    Operation::Remove
+                };
+                if args.is_empty() {
+                    return Ok(json!({ "reply": "no_nodes", "command": command }));
+                }
+                let mut action = Action {
+                    nodes: args,
+                    email,
+                    op,
+                };
+                action.normalize();
+                let (nodes, missing_nodes) = routes::action_nodes(&action, db).await?;
+                if !missing_nodes.is_empty() {
+                    return Ok(json!({ "reply": "unknown_nodes", "nodes": missing_nodes }));
+                }
+                action
+                    .run(
+                        db,
+                        Confirmation::now(&self.config().secrets, None, "telegram", None),
+                    )
+                    .await?;
+                self.metrics().action(op);
+                json!({ "reply": command.trim_start_matches('/'), "nodes": nodes })
+            }
+            "/list" => {
+                let watched_nodes = db
+                    .run(move |db| {
+                        monitors::table
+                            .filter(monitors::email.eq(&*email))
+                            .left_join(nodes::table.on(monitors::id.eq(nodes::id)))
+                            .order_by(monitors::id)
+                            .load::<MonitorNodeQuery>(db)
+                    })
+                    .await?;
+                json!({ "reply": "list", "watched_nodes": watched_nodes })
+            }
+            "/stop" => {
+                unlink_chat(db, chat_id).await?;
+                json!({ "reply": "stopped" })
+            }
+            _ => unreachable!("unknown Telegram command {}", command),
+        })
+    }
+}
+
+/// Notifications via Telegram
+#[rocket::async_trait]
+impl Notifier for Client {
+    async fn notify(
+        &self,
+        ctx: &Ctx<'_>,
+        db: &DbConn,
+        event: &Event<'_>,
+        subscribers: &[String],
+    ) -> Result<()> {
+        let chats = for_subscribers(db, subscribers).await?;
+        if chats.is_empty() {
+            return Ok(());
+        }
+        let text = event.text(ctx)?;
+        // Messages are sent in the background
+        for chat in chats {
+            self.send(chat.chat_id, text.clone());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mockserver::{MockRequest, MockServer};
+    use rocket::http::{ContentType, Header};
+    use rocket::local::asynchronous::Client as LocalClient;
+
+    /// Deliver an update with a message to the webhook, like Telegram does
+    async fn deliver(client: &LocalClient, secret: &str, chat_id: i64, text: &str) -> Status {
+        let update = json!({
+            "update_id": 1,
+            "message": { "message_id": 1, "chat": { "id": chat_id }, "text": text },
+        });
+        client
+            .post("/telegram")
+            .header(ContentType::JSON)
+            .header(Header::new(SECRET_HEADER, secret.to_owned()))
+            .body(update.to_string())
+            .dispatch()
+            .await
+            .status()
+    }
+
+    /// The reply the bot sent as the `count`th request to the Bot API
+    async fn reply(api: &MockServer, count: usize, chat_id: i64) -> String {
+        let requests = api.requests(count).await;
+        assert_eq!(requests.len(), count);
+        let MockRequest {
+            method, path, body, ..
+        } = &requests[count - 1];
+        assert_eq!(method, "POST");
+        assert_eq!(path, "/bot123:bot-token/sendMessage");
+        assert_eq!(body["chat_id"], chat_id);
+        body["text"].as_str().unwrap().to_owned()
+    }
+
+    fn monitor_via(db: &mut PgConnection, node: &str, email: &str) -> Option<Option<String>> {
+        monitors::table
+            .filter(monitors::id.eq(node))
+            .filter(monitors::email.eq(email))
+            .select(monitors::confirmed_via)
+            .first(db)
+            .optional()
+            .unwrap()
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs a PostgreSQL database in TEST_DATABASE_URL"]
+    async fn commands_via_webhook() {
+        let api = MockServer::start(|_| json!({ "ok": true, "result": {} })).await;
+        let client = crate::test_app(&format!(
+            "[ff-node-monitor.secrets]\n\
+             telegram_bot_token = \"123:bot-token\"\ntelegram_webhook_secret = \"hook-secret\"\n\
+             [ff-node-monitor.telegram]\nbot_name = \"test_bot\"\napi_url = \"{}\"",
+            api.url
+        ))
+        .await;
+        let db = DbConn::get_one(client.rocket()).await.unwrap();
+        let config = client.rocket().state::<Config>().unwrap();
+        let node = hex::encode(&random_secret().unwrap().as_bytes()[..6]);
+        let chat_id = i64::from_str_radix(&node, 16).unwrap();
+        let email = EmailAddress::new(format!("telegram-{}@example.org", node)).unwrap();
+        {
+            let node = node.clone();
+            db.run(move |db| {
+                diesel::insert_into(nodes::table)
+                    .values((
+                        nodes::id.eq(&node),
+                        nodes::name.eq("Testknoten"),
+                        nodes::online.eq(true),
+                    ))
+                    .execute(db)
+            })
+            .await
+            .unwrap();
+        }
+
+        // Updates without the secret are rejected
+        assert_eq!(
+            deliver(&client, "wrong", chat_id, "/list").await,
+            Status::Unauthorized
+        );
+
+        // Commands need a connected chat
+        assert_eq!(
+            deliver(&client, "hook-secret", chat_id, "/list").await,
+            Status::Ok
+        );
+        assert!(reply(&api, 1, chat_id)
+            .await
+            .contains("mit keiner E-Mail-Adresse"));
+
+        let token = create_link(&db, &config.secrets, &email).await.unwrap();
+        let start = format!("/start {}", token);
+        deliver(&client, "hook-secret", chat_id, &start).await;
+        assert!(reply(&api, 2, chat_id).await.contains(&*email));
+        // The link can only be used once
+        deliver(&client, "hook-secret", chat_id, &start).await;
+        assert!(reply(&api, 3, chat_id).await.contains("ungültig"));
+
+        let watch = format!("/watch@test_bot {}", node);
+        deliver(&client, "hook-secret", chat_id, &watch).await;
+        assert!(reply(&api, 4, chat_id).await.contains("jetzt überwacht"));
+        let (n, e) = (node.clone(), email.to_string());
+        let via = db.run(move |db| monitor_via(db, &n, &e)).await;
+        assert_eq!(via, Some(Some("telegram".to_owned())));
+
+        deliver(&client, "hook-secret", chat_id, "/list").await;
+        let listed = reply(&api, 5, chat_id).await;
+        assert_eq!(listed, format!("Testknoten ({}): online", node));
+
+        let unwatch = format!("/unwatch {}", node);
+        deliver(&client, "hook-secret", chat_id, &unwatch).await;
+        assert!(reply(&api, 6, chat_id)
+            .await
+            .contains("nicht mehr überwacht"));
+        let (n, e) = (node.clone(), email.to_string());
+        assert_eq!(db.run(move |db| monitor_via(db, &n, &e)).await, None);
+
+        deliver(&client, "hook-secret", chat_id, "/stop").await;
+        assert!(reply(&api, 7, chat_id)
+            .await
+            .contains("nicht mehr verbunden"));
+        assert!(list(&db, &email).await.unwrap().is_empty());
+        db.run(move |db| diesel::delete(nodes::table.find(node)).execute(db))
+            .await
+            .unwrap();
+    }
+}
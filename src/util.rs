@@ -15,24 +15,50 @@
 //  along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use std::borrow::Cow;
+use std::net::IpAddr;
 use std::ops::Deref;
 
 use anyhow::Result;
+use base64::Engine as _;
+use chrono::DateTime;
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
 
 use rocket::{
     request::{self, FromRequest, Outcome},
     Request,
 };
-use rocket_dyn_templates::Template;
+use rocket_dyn_templates::{handlebars::handlebars_helper, Template};
 
-use crate::config::Config;
+use crate::config::{Config, Secrets};
+
+/// Module for serde "with" to use hex encoding to byte arrays, for a map of key IDs to keys
+pub mod hex_signing_keys {
+    use std::collections::HashMap;
 
-/// Module for serde "with" to use hex encoding to byte arrays
-pub mod hex_signing_key {
     use hex;
     use ring::hmac;
     use serde::{de::Error, Deserialize, Deserializer};
 
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<HashMap<String, hmac::Key>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        HashMap::<String, String>::deserialize(deserializer)?
+            .into_iter()
+            .map(|(id, key)| {
+                let bytes = hex::decode(key).map_err(Error::custom)?;
+                Ok((id, hmac::Key::new(hmac::HMAC_SHA256, bytes.as_slice())))
+            })
+            .collect()
+    }
+}
+
+/// Module for serde "with" to use hex encoding to a single key
+pub mod hex_key {
+    use ring::hmac;
+    use serde::{de::Error, Deserialize, Deserializer};
+
     pub fn deserialize<'de, D>(deserializer: D) -> Result<hmac::Key, D::Error>
     where
         D: Deserializer<'de>,
@@ -42,6 +68,52 @@ pub mod hex_signing_key {
     }
 }
 
+/// Hash personal data that we only need to recognize again, not to read, before storing it.
+/// The hash is keyed, so that small inputs like IP addresses cannot be recovered by trying them
+/// all.
+pub fn hash_personal_data(secrets: &Secrets, data: &str) -> String {
+    hex::encode(hmac::sign(secrets.personal_data_key(), data.as_bytes()))
+}
+
+/// Compare secrets in a way that does not reveal through timing how much of them matched.
+/// `hmac::verify` is ring's constant-time comparison, so we compare the MAC of `a` with `b`.
+pub fn secrets_equal(secrets: &Secrets, a: &str, b: &str) -> bool {
+    let key = secrets.personal_data_key();
+    hmac::verify(key, a.as_bytes(), hmac::sign(key, b.as_bytes()).as_ref()).is_ok()
+}
+
+pub fn hash_client_ip(secrets: &Secrets, client_ip: Option<IpAddr>) -> Option<String> {
+    client_ip.map(|ip| hash_personal_data(secrets, &ip.to_string()))
+}
+
+/// Generate a random secret (e.g. for API tokens), 32 bytes encoded as URL-safe base64
+pub fn random_secret() -> Result<String> {
+    let mut bytes = [0u8; 32];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .map_err(|_| anyhow::anyhow!("failed to generate random secret"))?;
+    Ok(base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(bytes))
+}
+
+// Template helper to show a timestamp as a (German) date
+handlebars_helper!(date: |timestamp: str| {
+    match DateTime::parse_from_rfc3339(timestamp) {
+        Ok(timestamp) => timestamp.format("%d.%m.%Y").to_string(),
+        Err(_) => timestamp.to_owned(),
+    }
+});
+
+// Template helper to show a timestamp as a (German) date and time
+handlebars_helper!(datetime: |timestamp: str| {
+    match DateTime::parse_from_rfc3339(timestamp) {
+        Ok(timestamp) => timestamp.format("%d.%m.%Y %H:%M:%S").to_string(),
+        Err(_) => timestamp.to_owned(),
+    }
+});
+
+// Template helper to show a fraction as percentage
+handlebars_helper!(percent: |fraction: f64| format!("{:.0} %", fraction * 100.0));
+
 /// A request guard to get access to the rocket.
 pub struct Ctx<'r>(&'r rocket::Rocket<rocket::Orbit>);
 
@@ -61,7 +133,13 @@ impl<'r> FromRequest<'r> for Ctx<'r> {
     }
 }
 
-impl Ctx<'_> {
+impl<'r> Ctx<'r> {
+    /// Outside of requests, e.g. in tests
+    #[cfg(test)]
+    pub fn new(rocket: &'r rocket::Rocket<rocket::Orbit>) -> Self {
+        Ctx(rocket)
+    }
+
     pub fn config(&self) -> &Config {
         self.state::<Config>().unwrap()
     }
@@ -74,3 +152,22 @@ impl Ctx<'_> {
         Ok(Template::render(name, self.config().template_vals(vals)?))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compare_secrets() {
+        let secrets: Secrets = serde_json::from_value(serde_json::json!({
+            "action_signing_keys": { "1": "00" },
+            "action_signing_key_id": "1",
+            "personal_data_key": "00112233",
+        }))
+        .unwrap();
+        assert!(secrets_equal(&secrets, "secret", "secret"));
+        assert!(!secrets_equal(&secrets, "secret", "secreT"));
+        assert!(!secrets_equal(&secrets, "secret", "secret2"));
+        assert!(!secrets_equal(&secrets, "", "secret"));
+    }
+}
@@ -0,0 +1,385 @@
+//  ff-node-monitor -- Monitoring for Freifunk nodes
+//  Copyright (C) 2018  Ralf Jung <post AT ralfj DOT de>
+//
+//  This program is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU Affero General Public License as published by
+//  the Free Software Foundation, either version 3 of the License, or
+//  (at your option) any later version.
+//
+//  This program is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//  GNU Affero General Public License for more details.
+//
+//  You should have received a copy of the GNU Affero General Public License
+//  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use chrono::Utc;
+use diesel::prelude::*;
+use ring::hmac;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use url::{Host, Url};
+
+use crate::action::Signable;
+use crate::confirm::Confirmable;
+use crate::db::DbConn;
+use crate::email::EmailAddress;
+use crate::models::*;
+use crate::notify::{Event, Notifier};
+use crate::schema::*;
+use crate::util::{random_secret, Ctx};
+
+/// Header carrying the HMAC-SHA256 of the body, as `sha256=<hex>`
+pub const SIGNATURE_HEADER: &str = "X-FF-Node-Monitor-Signature";
+/// How often we try to deliver a payload, and how long we wait before each retry
+const RETRY_DELAYS: [u64; 3] = [0, 10, 60];
+
+/// Emailed to an address to add a webhook for that address, until the given time
+#[derive(Serialize, Deserialize)]
+pub struct AddWebhook {
+    pub email: EmailAddress,
+    pub url: String,
+    /// Unix timestamp
+    pub valid_until: i64,
+}
+
+impl Signable for AddWebhook {
+    const PURPOSE: &'static str = "webhook";
+}
+
+impl Confirmable for AddWebhook {
+    const EMAIL_TEMPLATE: &'static str = "webhook_link";
+    const REQUESTED_TEMPLATE: &'static str = "webhook_requested";
+    const CONFIRM_TEMPLATE: &'static str = "webhook_confirm";
+
+    fn email(&self) -> &EmailAddress {
+        &self.email
+    }
+
+    fn valid_until(&self) -> i64 {
+        self.valid_until
+    }
+
+    fn template_vals(&self) -> serde_json::Value {
+        json!({
+            "email": self.email,
+            "url": self.url,
+        })
+    }
+}
+
+/// Whether we are willing to send requests to this IP address: it must not point into our own
+/// network.
+pub fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_ipv4(ip),
+        IpAddr::V6(ip) => is_public_ipv6(ip),
+    }
+}
+
+fn is_public_ipv4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    !(ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        || ip.is_multicast()
+        // 0.0.0.0/8 ("this network")
+        || a == 0
+        // 100.64.0.0/10 (carrier-grade NAT)
+        || (a == 100 && (b & 0xc0) == 64))
+}
+
+fn is_public_ipv6(ip: Ipv6Addr) -> bool {
+    // IPv4-mapped (::ffff:0:0/96) and NAT64 (64:ff9b::/96) addresses lead to an IPv4 address
+    if let Some(ipv4) = ip.to_ipv4_mapped() {
+        return is_public_ipv4(ipv4);
+    }
+    let segments = ip.segments();
+    if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+        let [.., a, b, c, d] = ip.octets();
+        return is_public_ipv4(Ipv4Addr::new(a, b, c, d));
+    }
+    !(ip.is_loopback()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        // fc00::/7 (unique local)
+        || (segments[0] & 0xfe00) == 0xfc00
+        // fe80::/10 (link-local) and the deprecated fec0::/10 (site-local)
+        || (segments[0] & 0xffc0) == 0xfe80
+        || (segments[0] & 0xffc0) == 0xfec0
+        // The deprecated IPv4-compatible addresses ::/96
+        || segments[..6] == [0; 6])
+}
+
+/// Whether we are willing to send requests to this URL: it must be HTTP(S) and, if it contains an
+/// IP address, that address must be public.  Hostnames are checked when the request is sent,
+/// see [`client`].
+pub fn is_valid_url(url: &str) -> bool {
+    let url = match Url::parse(url) {
+        Ok(url) => url,
+        Err(_) => return false,
+    };
+    if url.scheme() != "https" && url.scheme() != "http" {
+        return false;
+    }
+    match url.host() {
+        None => false,
+        Some(Host::Domain(domain)) => {
+            let domain = domain.trim_end_matches('.');
+            domain != "localhost" && !domain.ends_with(".localhost")
+        }
+        Some(Host::Ipv4(ip)) => is_public_ip(ip.into()),
+        Some(Host::Ipv6(ip)) => is_public_ip(ip.into()),
+    }
+}
+
+/// Add a webhook for `email`.  Returns the secret used to sign the payloads, or `None` if `email`
+/// already has a webhook with that URL.
+pub async fn create(db: &DbConn, email: &EmailAddress, url: &str) -> Result<Option<String>> {
+    if !is_valid_url(url) {
+        bail!("invalid webhook URL: {}", url);
+    }
+    let secret = random_secret()?;
+    let email = email.clone();
+    let url = url.to_owned();
+    let secret2 = secret.clone();
+    let num_inserted = db
+        .run(move |db| {
+            diesel::insert_into(webhooks::table)
+                .values(&Webhook {
+                    email: &email,
+                    url: &url,
+                    secret: &secret2,
+                })
+                .on_conflict_do_nothing()
+                .execute(db)
+        })
+        .await?;
+    Ok(Some(secret).filter(|_| num_inserted > 0))
+}
+
+/// The webhooks of `email`, newest first
+pub async fn list(db: &DbConn, email: &EmailAddress) -> Result<Vec<WebhookQuery>> {
+    let email = email.clone();
+    Ok(db
+        .run(move |db| {
+            webhooks::table
+                .filter(webhooks::email.eq(&*email))
+                .order_by(webhooks::id.desc())
+                .load::<WebhookQuery>(db)
+        })
+        .await?)
+}
+
+/// Remove the webhook with the given ID, if it belongs to `email`.  Returns whether it existed.
+pub async fn remove(db: &DbConn, email: &EmailAddress, id: i64) -> Result<bool> {
+    let email = email.clone();
+    let num_deleted = db
+        .run(move |db| {
+            diesel::delete(
+                webhooks::table
+                    .filter(webhooks::id.eq(id))
+                    .filter(webhooks::email.eq(&*email)),
+            )
+            .execute(db)
+        })
+        .await?;
+    Ok(num_deleted > 0)
+}
+
+/// The webhooks of the given subscribers
+pub async fn for_subscribers(db: &DbConn, subscribers: &[String]) -> Result<Vec<WebhookQuery>> {
+    let subscribers = subscribers.to_vec();
+    Ok(db
+        .run(move |db| {
+            webhooks::table
+                .filter(webhooks::email.eq_any(subscribers))
+                .load::<WebhookQuery>(db)
+        })
+        .await?)
+}
+
+/// The payload sent when a node changes its state
+#[derive(Serialize)]
+struct NodeEventPayload<'a> {
+    /// Always "node_state_changed" for now
+    event: &'static str,
+    node: &'a NodeQuery,
+    /// The state before the change; the new one is part of `node`
+    was_online: bool,
+    node_url: &'a str,
+    /// The monitoring address this webhook belongs to
+    email: &'a str,
+    timestamp: chrono::DateTime<Utc>,
+}
+
+/// Compute the value of the signature header for `body`
+pub fn signature(secret: &str, body: &[u8]) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+    format!("sha256={}", hex::encode(hmac::sign(&key, body).as_ref()))
+}
+
+/// Send `payload` to the webhook in the background, retrying a few times on failure
+fn deliver(client: &reqwest::Client, webhook: &WebhookQuery, payload: &impl Serialize) {
+    // The URL may have been added before the current checks
+    if !is_valid_url(&webhook.url) {
+        return rocket::error!(
+            "Not delivering to webhook {}: URL is not allowed",
+            webhook.id
+        );
+    }
+    let body = serde_json::to_vec(payload).expect("failed to serialize webhook payload");
+    let signature = signature(&webhook.secret, &body);
+    let client = client.clone();
+    let (id, url) = (webhook.id, webhook.url.clone());
+    rocket::tokio::spawn(async move {
+        for delay in RETRY_DELAYS {
+            rocket::tokio::time::sleep(Duration::from_secs(delay)).await;
+            let response = client
+                .post(&url)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .header(SIGNATURE_HEADER, &signature)
+                .body(body.clone())
+                .send()
+                .await
+                .and_then(|response| response.error_for_status());
+            match response {
+                Ok(_) => return,
+                Err(e) => rocket::warn!("Delivering to webhook {} failed: {}", id, e),
+            }
+        }
+        rocket::error!("Giving up delivering to webhook {}", id);
+    });
+}
+
+/// Resolves hostnames, but only to public addresses.  Checking this when connecting (instead of
+/// when the URL is added) means that a hostname cannot later be changed to point into our network.
+struct PublicResolver;
+
+impl reqwest::dns::Resolve for PublicResolver {
+    fn resolve(&self, name: hyper::client::connect::dns::Name) -> reqwest::dns::Resolving {
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = rocket::tokio::net::lookup_host((name.as_str(), 0))
+                .await?
+                .filter(|addr| is_public_ip(addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(format!("{} has no public address", name.as_str()).into());
+            }
+            Ok(Box::new(addrs.into_iter()) as reqwest::dns::Addrs)
+        })
+    }
+}
+
+/// A client for requests to user-provided URLs (webhooks and push targets).  It only connects to
+/// public addresses and does not follow redirects; IP addresses in the URL itself still need to be
+/// checked with [`is_valid_url`].
+pub fn client() -> Result<reqwest::Client> {
+    Ok(reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .redirect(reqwest::redirect::Policy::none())
+        // A proxy would resolve the hostname itself
+        .no_proxy()
+        .dns_resolver(Arc::new(PublicResolver))
+        .build()?)
+}
+
+/// Notifications via webhooks
+pub struct WebhookNotifier {
+    client: reqwest::Client,
+}
+
+impl WebhookNotifier {
+    pub fn new() -> Result<Self> {
+        Ok(WebhookNotifier { client: client()? })
+    }
+}
+
+#[rocket::async_trait]
+impl Notifier for WebhookNotifier {
+    async fn notify(
+        &self,
+        _ctx: &Ctx<'_>,
+        db: &DbConn,
+        event: &Event<'_>,
+        subscribers: &[String],
+    ) -> Result<()> {
+        // Deliveries happen in the background
+        for hook in for_subscribers(db, subscribers).await? {
+            let payload = NodeEventPayload {
+                event: "node_state_changed",
+                node: event.node,
+                was_online: event.was_online,
+                node_url: &event.node_url,
+                email: &hook.email,
+                timestamp: event.timestamp,
+            };
+            deliver(&self.client, &hook, &payload);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accept_public_urls() {
+        assert!(is_valid_url("https://example.org/hook"));
+        assert!(is_valid_url("http://203.0.113.7:8080/hook"));
+        assert!(is_valid_url("http://[2001:db8::1]/hook"));
+    }
+
+    #[test]
+    fn reject_other_schemes() {
+        assert!(!is_valid_url("ftp://example.org/"));
+        assert!(!is_valid_url("file:///etc/passwd"));
+        assert!(!is_valid_url("not a url"));
+    }
+
+    #[test]
+    fn reject_internal_urls() {
+        for url in [
+            "http://localhost/",
+            "http://foo.localhost./",
+            "http://127.0.0.1/",
+            "http://0.0.0.0/",
+            "http://10.1.2.3/",
+            "http://100.64.0.1/",
+            "http://169.254.169.254/latest/meta-data/",
+            "http://192.168.0.1/",
+            "http://[::1]/",
+            "http://[::]/",
+            "http://[fd00::1]/",
+            "http://[fc00::1]/",
+            "http://[fe80::1]/",
+            "http://[::ffff:127.0.0.1]/",
+            "http://[::ffff:10.0.0.1]/",
+            "http://[64:ff9b::a9fe:a9fe]/",
+            "http://[::127.0.0.1]/",
+        ] {
+            assert!(!is_valid_url(url), "{}", url);
+        }
+    }
+
+    #[test]
+    fn resolver_skips_internal_addresses() {
+        use reqwest::dns::Resolve;
+
+        let rt = rocket::tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let name = "localhost".parse().unwrap();
+        assert!(rt.block_on(PublicResolver.resolve(name)).is_err());
+    }
+}
@@ -91,9 +91,13 @@ sources = "https://github.com/freifunk-saar/ff-node-monitor"
 #stylesheet = "https://..."
 
 [global.ff-node-monitor.secrets]
-# Key used to sign data for confirmation emails.  Generate this key with
-# `openssl rand -hex 32`.
-action_signing_key = "$(openssl rand -hex 32)"
+# Keys used to sign data for confirmation emails, indexed by a key ID of your choice.  Generate
+# each key with `openssl rand -hex 32`.
+action_signing_keys = { 1 = "$(openssl rand -hex 32)" }
+# The ID of the key used to sign new confirmation links.
+action_signing_key_id = "1"
+# Key used to hash personal data (like client IP addresses) before storing it.
+personal_data_key = "$(openssl rand -hex 32)"
 # Optional: Host to submit emails to.  That host must accept email with arbitrary destination
 # from this service.  Unless this is "localhost", the connection will be encrypted via STARTTLS.
 smtp_host = "localhost"